@@ -1,10 +1,15 @@
 use directories::ProjectDirs;
+use libp2p::{multiaddr::Protocol, Multiaddr};
 use std::{fs, io, path::PathBuf};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub network: NetworkConfig,
+    #[serde(default)]
+    pub files: FileConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,9 +17,158 @@ pub struct NetworkConfig {
     pub ip_version: IpVersion,
     pub listen_ip: String,
     pub listen_port: u16,
+    /// The bootstrap node to join the network through. None is built in, set
+    /// these three or `bootstrap_addrs` to a running vanadinite node and the
+    /// peer id it prints on start.
     pub bootstrap_ip: Option<String>,
     pub bootstrap_port: Option<u16>,
     pub bootstrap_peer_id: Option<String>,
+    /// More bootstrap nodes as full multiaddrs, `/dns4`, `/dns6` and `/dnsaddr`
+    /// included. All of them are dialed together with the one above.
+    #[serde(default)]
+    pub bootstrap_addrs: Vec<String>,
+    /// Known peers not seen for this many days are dropped from the peer store.
+    #[serde(default = "default_peer_ttl_days")]
+    pub peer_ttl_days: u64,
+    /// How often Kademlia is bootstrapped again to refresh the routing table.
+    #[serde(default = "default_bootstrap_interval")]
+    pub bootstrap_interval_secs: u64,
+    /// Below this many connected peers the network is reported as degraded.
+    #[serde(default = "default_min_connected_peers")]
+    pub min_connected_peers: usize,
+}
+
+fn default_peer_ttl_days() -> u64 {
+    30
+}
+
+fn default_bootstrap_interval() -> u64 {
+    5 * 60
+}
+
+fn default_min_connected_peers() -> usize {
+    1
+}
+
+impl NetworkConfig {
+    /// Every configured bootstrap node, the single `bootstrap_*` one first.
+    pub fn bootstrap_nodes(&self) -> Result<Vec<Multiaddr>, String> {
+        let mut nodes = Vec::new();
+
+        if let (Some(ip), Some(port), Some(peer_id)) =
+            (&self.bootstrap_ip, self.bootstrap_port, &self.bootstrap_peer_id)
+        {
+            let addr = match self.ip_version {
+                IpVersion::Ipv4 => format!("/ip4/{}/tcp/{}/ws/p2p/{}", ip, port, peer_id),
+                IpVersion::Ipv6 => format!("/ip6/{}/tcp/{}/ws/p2p/{}", ip, port, peer_id),
+            };
+            nodes.push(addr.parse::<Multiaddr>().map_err(|e| format!("Invalid bootstrap node: {e}"))?);
+        }
+
+        for addr in &self.bootstrap_addrs {
+            let addr: Multiaddr = addr
+                .parse()
+                .map_err(|e| format!("Invalid bootstrap address {addr}: {e}"))?;
+
+            // A dnsaddr record carries the peer ids itself
+            let dnsaddr = matches!(addr.iter().next(), Some(Protocol::Dnsaddr(_)));
+            if !dnsaddr && !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(format!("Bootstrap address {addr} must end in /p2p/<PeerId>"));
+            }
+
+            if !nodes.contains(&addr) {
+                nodes.push(addr);
+            }
+        }
+
+        Ok(nodes)
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileConfig {
+    /// Largest attachment we send or accept, in bytes.
+    pub max_file_size: u64,
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 100 * 1024 * 1024,
+        }
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// Used for new passwords, and stored keys are re-wrapped with it on unlock.
+    pub kdf: KdfParams,
+    /// Lock after this many seconds without user activity, 0 never locks.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout_secs: u64,
+}
+
+fn default_idle_timeout() -> u64 {
+    15 * 60
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            kdf: KdfParams::default(),
+            idle_timeout_secs: default_idle_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfAlgorithm {
+    Argon2id,
+    Argon2i,
+    Argon2d,
+}
+
+/// Key derivation settings, also recorded next to every wrapped key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// What `Argon2::default()` used before parameters were recorded.
+    pub fn legacy() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_kib: 4096,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+
+    /// Whether a key derived with `self` should be re-derived with `target`.
+    /// Never lowers the cost, a weaker config only applies to new passwords.
+    pub fn should_upgrade_to(&self, target: &KdfParams) -> bool {
+        self != target
+            && target.memory_kib >= self.memory_kib
+            && target.iterations >= self.iterations
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
 }
 
 
@@ -57,6 +211,36 @@ impl Config {
             );
         }
 
+        if let Some(peer_id) = &b.bootstrap_peer_id {
+            peer_id
+                .parse::<libp2p::PeerId>()
+                .map_err(|e| format!("Invalid bootstrap peer id: {e}"))?;
+        }
+        self.network.bootstrap_nodes()?;
+
+        if self.network.peer_ttl_days == 0 {
+            return Err("Peer TTL cannot be 0 days".into());
+        }
+
+        if self.network.bootstrap_interval_secs < 10 {
+            return Err("Bootstrap interval must be at least 10 seconds".into());
+        }
+
+        if self.files.max_file_size == 0 {
+            return Err("Max file size cannot be 0".into());
+        }
+
+        let kdf = &self.security.kdf;
+        if kdf.iterations == 0 {
+            return Err("KDF iterations cannot be 0".into());
+        }
+        if kdf.parallelism == 0 || kdf.parallelism > 64 {
+            return Err("KDF parallelism must be between 1 and 64".into());
+        }
+        if kdf.memory_kib < 8 * kdf.parallelism {
+            return Err("KDF memory must be at least 8 KiB per lane".into());
+        }
+
         Ok(())
     }
 
@@ -98,10 +282,16 @@ impl Default for Config {
                 ip_version: IpVersion::Ipv4,
                 listen_ip: "0.0.0.0".into(),
                 listen_port: 8000,
-                bootstrap_ip: Some("127.0.0.1".to_string()),
-                bootstrap_port: Some(8000),
-                bootstrap_peer_id: Some("12D3KooWJ5VBBryqyPrBXAd28fk9KsH3pXdiXshH6gpsLWWi6WiH".to_string()),
+                bootstrap_ip: None,
+                bootstrap_port: None,
+                bootstrap_peer_id: None,
+                bootstrap_addrs: vec![],
+                peer_ttl_days: default_peer_ttl_days(),
+                bootstrap_interval_secs: default_bootstrap_interval(),
+                min_connected_peers: default_min_connected_peers(),
             },
+            files: FileConfig::default(),
+            security: SecurityConfig::default(),
         }
     }
 }
@@ -1,26 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock as StdRwLock};
+use std::time::{Duration, Instant};
 use keyring::Entry;
 use once_cell::sync::OnceCell;
 use libp2p::{
     futures::StreamExt,
     identity,
+    swarm::dial_opts::DialOpts,
+    Multiaddr,
     PeerId,
 };
 use serde::{Deserialize, Serialize};
 use tauri::{Emitter, Manager};
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, mpsc, oneshot};
 use tracing::{warn};
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::config::Config;
-use crate::message::chat::chat_store::{chat_dir, load_chat, save_chat};
-use crate::security::security::{ derive_storage_key, load_salt_from_disk, load_storage_key, save_salt_to_disk, save_storage_key};
-use crate::node_identity::identity::{ load_or_create_identity};
-use crate::node_identity::peers::load_peers_from_disk;
-use crate::p2p::command::{P2PCommand, handle_command};
-use crate::p2p::connection_p2p::P2P;
+use crate::config::{Config, KdfParams};
+use crate::message::chat::chat_store::{append_chat, chat_dir, chat_names, has_message, load_chat, load_chat_page, quarantine_chat, restore_chat, verify_chat, HistoryCursor, StoreError};
+use crate::message::chat::outbox::{enqueue_outbox, load_outbox, outbox_dir, outbox_peers, remove_from_outbox};
+use crate::message::file::file_store::{append_chunk, downloaded_bytes, export_file, finalize_download, import_file, is_valid_file_id, load_file_index, read_chunk, save_file_index, update_file_index, Attachment, FileRecord};
+use crate::message::search::search_index::{forget_chat, search_messages as search_index, SearchResult};
+use crate::message::group::group_store::{group_history_name, load_groups, save_groups, Group, GroupInfo};
+use crate::message::message::{ChatEnvelope, DeliveryStatus, DeviceCertificate, FileResponse, GreetResponse, GroupEnvelope, GroupInvite};
+use crate::security::group::{open_group_message, seal_group_message, unwrap_group_key, wrap_group_key};
+use crate::security::e2e::{open_envelope, seal_message};
+use crate::security::file::{open_chunk, seal_chunk};
+use crate::security::session_store::{load_prekey, load_sessions, save_prekey, save_sessions, session_dir, session_peers, SESSION_LOCK};
+use crate::security::x3dh::{generate_prekey, x3dh_initiate, PreKeyBundle};
+use crate::security::safety::{account_fingerprint, matches_safety_number, safety_number, SafetyNumber};
+use crate::security::device::{consent_to_link, issue_certificate, verify_certificate, verify_consent};
+use crate::security::backup::{read_backup, restore_files, write_backup, BackupChat, BackupPayload};
+use crate::security::rekey::{recover_interrupted_rekey, rekey_storage};
+use crate::security::security::{ derive_storage_key, generate_storage_key, load_salt_from_disk, load_stored_key, remove_salt_from_disk, clear_current_key, replace_current_key, save_wrapped_key, set_current_key, unwrap_storage_key, wrap_storage_key, StoredKey};
+use crate::node_identity::contacts::{contact_entry, load_contacts, save_contacts, update_contacts, Contact};
+use crate::node_identity::devices::{load_devices, save_devices, update_devices};
+use crate::node_identity::identity::{ export_identity, import_identity, load_or_create_identity};
+use crate::node_identity::peers::{load_peers_from_disk, save_peers_to_disk, PeerReputation};
+use crate::p2p::agent::Agent;
+use crate::p2p::command::{DiscoveredPeer, P2PCommand, handle_command, retry_pending_chats};
+use crate::p2p::connection_p2p::{KAD_QUERY_TIMEOUT, P2P};
 use crate::p2p::event::{P2PEvent, handle_swarm_event};
+use crate::p2p::health::{HealthStatus, network_health, redial_bootstrap, refresh_routing};
 
 // Crate
 mod message;
@@ -32,6 +54,13 @@ mod config;
 static APP_DATA_DIR: OnceCell<PathBuf> = OnceCell::new();
 const SERVICE: &str = "vanadinite-chat";
 const KEY_NAME: &str = "storage-key";
+const RETRY_TICK: Duration = Duration::from_secs(1);
+const MAX_PAGE_SIZE: usize = 200;
+const IDLE_CHECK: Duration = Duration::from_secs(15);
+const MAX_LOCKED_INBOX: usize = 1000;
+const PEER_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MAX_STARTUP_DIALS: usize = 8;
+const HEALTH_CHECK: Duration = Duration::from_secs(30);
 
 
 // Struct
@@ -39,11 +68,23 @@ struct AppState {
     pub identity: identity::Keypair,
     pub tx: mpsc::Sender<P2PCommand>,
     pub peer_store: Arc<PeerStore>,
-    pub entry: Entry
+    pub entry: Entry,
+    /// Messages already handed to the swarm, so an outbox flush does not seal them twice
+    pub in_flight: Mutex<HashSet<(PeerId, String)>>,
 }
 
 struct CredentialState {
-    pub storage_key: Option<[u8; 32]>,
+    /// `None` while locked, read through `current_storage_key`
+    pub storage_key: StdRwLock<Option<[u8; 32]>>,
+    pub last_activity: Mutex<Instant>,
+    /// Arrived while locked, handled on the next unlock. Chats are not held
+    /// here, they are deferred back to the sender's outbox instead.
+    pub locked_inbox: Mutex<VecDeque<LockedInbound>>,
+}
+
+enum LockedInbound {
+    GroupMessage { envelope: GroupEnvelope },
+    GroupInvite { peer: PeerId, invite: GroupInvite },
 }
 
 
@@ -54,6 +95,11 @@ pub struct StoredPeer {
     last_seen: i64,
     success: u32,
     fail: u32,
+    /// Failures since the last success, drives the dial backoff.
+    #[serde(default)]
+    fail_streak: u32,
+    #[serde(default)]
+    last_fail: i64,
 }
 
 pub struct PeerStore{
@@ -67,9 +113,20 @@ pub struct ChatMessage {
     to: PeerId,
     timestamp: i64,
     content: String,
+    #[serde(default)]
+    attachment: Option<Attachment>,
     // direction: MessageDirection,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupMessage {
+    id: String,
+    group_id: String,
+    from: PeerId,
+    timestamp: i64,
+    content: String,
+}
+
 struct P2PService {
     event_tx: mpsc::Sender<P2PEvent>,
 }
@@ -78,40 +135,729 @@ struct P2PService {
 
 // Function / Tools
 
+/// `Ok` once the message is stored, or safe to drop. On `Err` the sender
+/// still has it and tries again later with the same envelope, so the ratchet
+/// is only saved once the message is stored.
 async fn on_message_received(
     app: &tauri::AppHandle,
     peer: PeerId,
-    msg: ChatMessage,
-) {
+    envelope: ChatEnvelope,
+) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("recipient is locked")?;
+    let storage_key = &storage_key;
+
+    if is_blocked(storage_key, peer) {
+        log::info!("dropping message {} from blocked {peer}", envelope.id);
+        return Ok(());
+    }
+
+    let guard = SESSION_LOCK.lock().await;
+
+    // A retry after a lost ack carries a message key the saved ratchet has
+    // already used, it must be answered without decrypting
+    match has_message(&contact_of(storage_key, peer).to_string(), &envelope.id, storage_key, chat_dir()) {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        // Set aside by `store_chat` below
+        Err(e) if e.is_corrupt() => {}
+        Err(e) => return Err(format!("cannot check message {}: {e}", envelope.id)),
+    }
+
+    let (msg, sessions) = load_sessions(&peer.to_string(), storage_key, session_dir())
+        .map_err(|e| e.to_string())
+        .and_then(|mut sessions| {
+            let prekey = load_prekey(storage_key).map_err(|e| e.to_string())?;
+            let msg = open_envelope(&app.state::<AppState>().identity, &mut sessions, prekey.as_ref(), &envelope)?;
+            Ok((msg, sessions))
+        })
+        .map_err(|e| format!("cannot open message {}: {e}", envelope.id))?;
+
+    if let Some(cert) = envelope.device.as_ref().filter(|c| c.device == peer) {
+        match verify_certificate(cert) {
+            Ok(()) => match update_devices(storage_key, |store| {
+                let added = store.add_certificate(cert.as_ref().clone());
+                (added, store.disputed.contains(cert))
+            }) {
+                Ok((true, _)) => check_contact_keys(app, storage_key, cert.account),
+                Ok((false, true)) => {
+                    warn!("{peer} claims to belong to {}, another account has it", cert.account);
+                    app.emit("device-disputed", (peer.to_string(), cert.account.to_string())).ok();
+                }
+                Ok((false, false)) => {}
+                Err(e) => warn!("failed to record device {peer}: {e}"),
+            },
+            Err(reason) => warn!("ignoring device certificate from {peer}: {reason}"),
+        }
+    }
+    // Messages from any device of a contact share one history
+    let contact = contact_of(storage_key, peer);
+
+    // Retries can deliver the same message twice when an ack gets lost
+    let stored = store_chat(app, &contact.to_string(), &msg.id, &msg, storage_key)
+        .map_err(|e| format!("failed to store message {}: {e}", msg.id))?;
+    save_sessions(&peer.to_string(), &sessions, storage_key, session_dir())
+        .map_err(|e| format!("failed to save session for message {}: {e}", msg.id))?;
+    drop(guard);
+
+    if !stored {
+        return Ok(());
+    }
+
+    app.emit("message-received", (contact.to_string(), msg.clone())).ok();
+
+    if let Some(attachment) = msg.attachment {
+        let file_id = attachment.file_id.clone();
+        if let Err(e) = register_download(app, peer, attachment).await {
+            warn!("not downloading {file_id} from {peer}: {e}");
+            app.emit("file-failed", (peer.to_string(), file_id, e)).ok();
+        }
+    }
+    Ok(())
+}
+
+/// The account `peer` is a device of, `peer` itself when it is not linked.
+fn contact_of(storage_key: &[u8; 32], peer: PeerId) -> PeerId {
+    load_devices(storage_key)
+        .map(|store| store.account_of(&peer))
+        .unwrap_or(peer)
+}
+
+/// Whether the account `peer` belongs to is a blocked contact.
+fn is_blocked(storage_key: &[u8; 32], peer: PeerId) -> bool {
+    let contact = contact_of(storage_key, peer);
+    load_contacts(storage_key)
+        .map(|contacts| contacts.iter().any(|c| c.blocked && c.peer_id == contact))
+        .unwrap_or(false)
+}
+
+/// Hands the swarm every device of every blocked contact. The list is
+/// encrypted, so blocking only takes hold in the swarm once unlocked.
+async fn apply_blocklist(app: &tauri::AppHandle) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let devices = load_devices(&storage_key).map_err(|e| e.to_string())?;
+
+    for contact in load_contacts(&storage_key).map_err(|e| e.to_string())? {
+        if contact.blocked {
+            for peer in devices.devices_of(&contact.peer_id) {
+                let _ = app.state::<AppState>().tx.send(P2PCommand::BlockPeer { peer }).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fingerprint of `account` and every device we know it has.
+fn fingerprint_of(storage_key: &[u8; 32], account: PeerId) -> Result<[u8; 30], String> {
+    let devices = load_devices(storage_key).map_err(|e| e.to_string())?;
+    account_fingerprint(&account, &devices.devices_of(&account))
+}
+
+/// Drops the verified flag of `account` if its keys no longer match the ones
+/// the user verified, and tells the user.
+fn check_contact_keys(app: &tauri::AppHandle, storage_key: &[u8; 32], account: PeerId) {
+    let current = match fingerprint_of(storage_key, account) {
+        Ok(fingerprint) => hex::encode(fingerprint),
+        Err(e) => {
+            warn!("cannot fingerprint {account}: {e}");
+            return;
+        }
+    };
+
+    let changed = update_contacts(storage_key, |contacts| {
+        let contact = contacts.iter_mut().find(|c| c.peer_id == account && c.verified)?;
+        if contact.verified_fingerprint.as_deref() == Some(current.as_str()) {
+            return None;
+        }
+        contact.verified = false;
+        contact.verified_fingerprint = None;
+        Some(contact.clone())
+    });
+
+    match changed {
+        Ok(Some(contact)) => {
+            warn!("keys of verified contact {account} changed");
+            app.emit("contact-key-changed", contact).ok();
+        }
+        Ok(None) => {}
+        Err(e) => warn!("failed to check keys of {account}: {e}"),
+    }
+}
+
+/// History a frontend peer id refers to, any device resolves to its account.
+fn history_name(storage_key: &[u8; 32], peer_id: &str) -> String {
+    match peer_id.parse::<PeerId>() {
+        Ok(peer) => contact_of(storage_key, peer).to_string(),
+        Err(_) => peer_id.to_string(),
+    }
+}
+
+/// Tells `peer` which devices make up our account, if we have more than one
+/// and `peer` is a contact. Anyone else has no business knowing.
+async fn announce_devices(app: &tauri::AppHandle, peer: PeerId) {
+    let Some(storage_key) = current_storage_key(app) else {
+        return;
+    };
+    let account = contact_of(&storage_key, peer);
+    let is_contact = load_contacts(&storage_key)
+        .is_ok_and(|contacts| contacts.iter().any(|c| c.peer_id == account && !c.blocked));
+    if !is_contact {
+        return;
+    }
+    let state = app.state::<AppState>();
+    let local = state.identity.public().to_peer_id();
+
+    let certs = match load_devices(&storage_key) {
+        Ok(store) => store.accounts.get(&store.local_account(&local)).cloned().unwrap_or_default(),
+        Err(e) => {
+            warn!("failed to read device store: {e}");
+            return;
+        }
+    };
+    if !certs.is_empty() && !certs.iter().any(|c| c.device == peer) {
+        let _ = state.tx.send(P2PCommand::SendDeviceList { peer, certs }).await;
+    }
+}
+
+/// Takes a verified device list from `peer`. This is also how a device we
+/// linked, or one we removed, learns about it.
+async fn on_device_list(app: &tauri::AppHandle, peer: PeerId, account: PeerId, certs: Vec<DeviceCertificate>) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let local = app.state::<AppState>().identity.public().to_peer_id();
+
+    // On the primary our own list is the only one that counts
+    if account == local {
+        return Ok(());
+    }
+
+    let (change, disputed) = update_devices(&storage_key, |store| {
+        if store.removed.contains(&peer) {
+            return (None, false);
+        }
+
+        let mut change = None;
+        if store.pending_link == Some(account) {
+            if let Some(cert) = certs.iter().find(|c| c.device == local) {
+                store.own = Some(cert.clone());
+                store.pending_link = None;
+                change = Some("device-linked");
+            }
+        } else if peer == account && store.own.as_ref().is_some_and(|c| c.account == account) && !certs.iter().any(|c| c.device == local) {
+            store.own = None;
+            change = Some("device-unlinked");
+        }
+
+        let disputed = store.apply_device_list(&peer, account, certs);
+        (change, disputed)
+    }).map_err(|e| e.to_string())?;
+
+    if let Some(event) = change {
+        log::info!("{event}: {account}");
+        app.emit(event, account.to_string()).ok();
+    }
+    if disputed {
+        warn!("{account} lists devices another account has");
+        app.emit("device-disputed", (peer.to_string(), account.to_string())).ok();
+    }
+    check_contact_keys(app, &storage_key, account);
+    Ok(())
+}
+
+/// Keeps the consent of a device asking to join our account until the user
+/// approves it with `approve_device`.
+fn on_link_request(app: &tauri::AppHandle, peer: PeerId, consent: Vec<u8>) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let local = app.state::<AppState>().identity.public().to_peer_id();
+    verify_consent(&local, &peer, &consent)?;
+
+    update_devices(&storage_key, |store| store.link_requests.insert(peer, consent))
+        .map_err(|e| e.to_string())?;
+    app.emit("device-link-requested", peer.to_string()).ok();
+    Ok(())
+}
+
+/// Appends to a history, setting the old file aside first if it turns out to
+/// be unreadable so new messages are not lost along with it.
+fn store_chat<T: Serialize>(app: &tauri::AppHandle, name: &str, id: &str, msg: &T, storage_key: &[u8; 32]) -> Result<bool, StoreError> {
+    match append_chat(name, id, msg, storage_key, chat_dir()) {
+        Err(e) if e.is_corrupt() => {
+            on_chat_load_error(app, name, &e);
+            append_chat(name, id, msg, storage_key, chat_dir())
+        }
+        result => result,
+    }
+}
+
+/// Moves an undecryptable history to the recovery folder and reports it.
+fn on_chat_load_error(app: &tauri::AppHandle, name: &str, err: &StoreError) {
+    if !err.is_corrupt() {
+        return;
+    }
+    if let Err(e) = quarantine_chat(name, chat_dir()) {
+        warn!("failed to quarantine chat {name}: {e}");
+    }
+    if let Some(storage_key) = current_storage_key(app) {
+        if let Err(e) = forget_chat(name, &storage_key) {
+            warn!("failed to drop chat {name} from the search index: {e}");
+        }
+    }
+    app.emit("chat-load-failed", (name.to_string(), err.to_string())).ok();
+}
+
+/// Checks every history after unlock, so broken ones are reported up front
+/// instead of the first time the user opens them.
+fn check_chats(app: &tauri::AppHandle) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let names = chat_names(chat_dir());
+
+    let failed: Vec<(String, StoreError)> = names
+        .iter()
+        .filter_map(|name| verify_chat(name, &storage_key, chat_dir()).err().map(|e| (name.clone(), e)))
+        .filter(|(_, e)| e.is_corrupt())
+        .collect();
+
+    // Every chat failing at once points at the key rather than the files, keep them in place
+    if names.len() > 1 && failed.len() == names.len() {
+        for (name, e) in failed {
+            app.emit("chat-load-failed", (name, e.to_string())).ok();
+        }
+        return Err("no chat history could be decrypted with the current key".into());
+    }
+
+    for (name, e) in failed {
+        on_chat_load_error(app, &name, &e);
+    }
+    Ok(())
+}
+
+/// A copy of the storage key that is wiped when dropped.
+fn current_storage_key(app: &tauri::AppHandle) -> Option<Zeroizing<[u8; 32]>> {
+    app.try_state::<CredentialState>()?.storage_key.read().unwrap().map(Zeroizing::new)
+}
+
+fn set_storage_key(app: &tauri::AppHandle, key: Option<[u8; 32]>) {
+    if let Some(key) = &key {
+        set_current_key(key);
+    }
+    *app.state::<CredentialState>().storage_key.write().unwrap() = key;
+    touch_activity(app);
+}
+
+fn touch_activity(app: &tauri::AppHandle) {
+    *app.state::<CredentialState>().last_activity.lock().unwrap() = Instant::now();
+}
+
+/// Wipes the storage key from memory. Copies held by work already running
+/// are wiped as that work finishes, and can no longer write anything.
+fn lock_storage(app: &tauri::AppHandle, reason: &str) {
+    // Before the key itself, `change_password` takes them in this order
+    clear_current_key();
     let cred = app.state::<CredentialState>();
+    let mut storage_key = cred.storage_key.write().unwrap();
+    if storage_key.is_none() {
+        return;
+    }
+    storage_key.zeroize();
+    drop(storage_key);
+
+    log::info!("app locked: {reason}");
+    app.emit("app-locked", reason).ok();
+}
 
-    let Some(storage_key) = cred.storage_key.as_ref() else {
-        warn!("Message received but app is locked, message skipped");
+fn lock_if_idle(app: &tauri::AppHandle) {
+    let timeout = Config::load().map(|cfg| cfg.security.idle_timeout_secs).unwrap_or_default();
+    if timeout == 0 || current_storage_key(app).is_none() {
+        return;
+    }
+
+    let idle = app.state::<CredentialState>().last_activity.lock().unwrap().elapsed();
+    if idle >= Duration::from_secs(timeout) {
+        lock_storage(app, "idle");
+    }
+}
+
+fn hold_while_locked(app: &tauri::AppHandle, inbound: LockedInbound) {
+    let cred = app.state::<CredentialState>();
+    let mut inbox = cred.locked_inbox.lock().unwrap();
+    if inbox.len() >= MAX_LOCKED_INBOX {
+        warn!("locked inbox full, dropping the oldest message");
+        inbox.pop_front();
+    }
+    inbox.push_back(inbound);
+}
+
+/// Handles everything that arrived while the app was locked.
+async fn drain_locked_inbox(app: &tauri::AppHandle) {
+    let held: Vec<LockedInbound> = app.state::<CredentialState>().locked_inbox.lock().unwrap().drain(..).collect();
+    if !held.is_empty() {
+        log::info!("handling {} message(s) received while locked", held.len());
+    }
+
+    for inbound in held {
+        match inbound {
+            LockedInbound::GroupMessage { envelope } => {
+                let id = envelope.id.clone();
+                if let Err(e) = on_group_message(app, envelope).await {
+                    warn!("dropping group message {id}: {e}");
+                }
+            }
+            LockedInbound::GroupInvite { peer, invite } => {
+                if let Err(e) = on_group_invite(app, peer, invite).await {
+                    warn!("ignoring group invite from {peer}: {e}");
+                }
+            }
+        }
+    }
+}
+
+async fn on_group_message(app: &tauri::AppHandle, envelope: GroupEnvelope) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    if is_blocked(&storage_key, envelope.from) {
+        return Err("sender is blocked".into());
+    }
+    let mut groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+
+    let group = groups
+        .iter_mut()
+        .find(|g| g.id == envelope.group_id && g.joined)
+        .ok_or("message for a group we are not in")?;
+
+    let msg = open_group_message(group, &envelope)?;
+
+    // Holding the group key is what makes someone a member, learn who they are
+    if !group.members.contains(&msg.from) {
+        group.members.push(msg.from);
+        save_groups(&groups, &storage_key).map_err(|e| e.to_string())?;
+    }
+
+    let appended = store_chat(app, &group_history_name(&msg.group_id), &msg.id, &msg, &storage_key)
+        .map_err(|e| e.to_string())?;
+    if !appended {
+        return Ok(());
+    }
+
+    app.emit("group-message-received", (msg.group_id.clone(), msg)).ok();
+    Ok(())
+}
+
+async fn on_group_invite(app: &tauri::AppHandle, peer: PeerId, invite: GroupInvite) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    if is_blocked(&storage_key, peer) {
+        return Err("inviter is blocked".into());
+    }
+    let state = app.state::<AppState>();
+    let key = unwrap_group_key(&state.identity, &peer, &invite)?;
+
+    let mut groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+    if groups.iter().any(|g| g.id == invite.group_id) {
+        return Ok(());
+    }
+
+    let mut members = invite.members;
+    let local = state.identity.public().to_peer_id();
+    for member in [peer, local] {
+        if !members.contains(&member) {
+            members.push(member);
+        }
+    }
+
+    let group = Group {
+        id: invite.group_id,
+        name: invite.name,
+        admin: invite.admin,
+        members,
+        key,
+        joined: false,
+    };
+    app.emit("group-invite", (peer.to_string(), group.info())).ok();
+
+    groups.push(group);
+    save_groups(&groups, &storage_key).map_err(|e| e.to_string())
+}
+
+async fn subscribe_joined_groups(app: &tauri::AppHandle) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+
+    for group in load_groups(&storage_key).map_err(|e| e.to_string())? {
+        if group.joined {
+            let _ = state.tx.send(P2PCommand::JoinGroup { group_id: group.id }).await;
+        }
+    }
+    Ok(())
+}
+
+/// Seals `msg` with the peer's ratchet session and hands it to the swarm.
+/// Without a session this asks the peer for its prekeys instead and returns
+/// `false`, the message then waits in the outbox for the handshake.
+async fn dispatch_chat(app: &tauri::AppHandle, peer: PeerId, msg: &ChatMessage) -> Result<bool, String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+
+    let sealed = {
+        let _guard = SESSION_LOCK.lock().await;
+        let mut sessions = load_sessions(&peer.to_string(), &storage_key, session_dir())
+            .map_err(|e| e.to_string())?;
+
+        match sessions.current_mut() {
+            Some(session) => {
+                let envelope = seal_message(&state.identity, session, msg)?;
+                save_sessions(&peer.to_string(), &sessions, &storage_key, session_dir())
+                    .map_err(|e| e.to_string())?;
+                Some(envelope)
+            }
+            None => None,
+        }
+    };
+
+    let Some(mut envelope) = sealed else {
+        let _ = state.tx.send(P2PCommand::RequestPreKeys { peer }).await;
+        return Ok(false);
+    };
+    envelope.device = load_devices(&storage_key).map_err(|e| e.to_string())?.own.map(Box::new);
+
+    state.in_flight.lock().unwrap().insert((peer, msg.id.clone()));
+    let _ = state.tx.send(P2PCommand::SendChat { peer, envelope }).await;
+    Ok(true)
+}
+
+async fn flush_outbox(app: &tauri::AppHandle, peer: PeerId) {
+    let Some(storage_key) = current_storage_key(app) else {
+        return;
+    };
+
+    let pending = match load_outbox(&peer.to_string(), &storage_key, outbox_dir()) {
+        Ok(pending) => pending,
+        Err(e) => {
+            warn!("failed to read outbox for {peer}: {e}");
+            return;
+        }
+    };
+
+    let state = app.state::<AppState>();
+    let pending: Vec<ChatMessage> = {
+        let in_flight = state.in_flight.lock().unwrap();
+        pending.into_iter().filter(|m| !in_flight.contains(&(peer, m.id.clone()))).collect()
+    };
+
+    if pending.is_empty() {
+        return;
+    }
+
+    log::info!("flushing {} queued message(s) to {peer}", pending.len());
+    for msg in pending {
+        match dispatch_chat(app, peer, &msg).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                warn!("cannot send queued message {} to {peer}: {e}", msg.id);
+                break;
+            }
+        }
+    }
+}
+
+async fn start_session(app: &tauri::AppHandle, peer: PeerId, bundle: PreKeyBundle) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let identity = &app.state::<AppState>().identity;
+
+    let _guard = SESSION_LOCK.lock().await;
+    let mut sessions = load_sessions(&peer.to_string(), &storage_key, session_dir())
+        .map_err(|e| e.to_string())?;
+
+    if sessions.current_mut().is_some() {
+        return Ok(());
+    }
+
+    sessions.push(x3dh_initiate(identity, &peer, &bundle)?);
+    save_sessions(&peer.to_string(), &sessions, &storage_key, session_dir())
+        .map_err(|e| e.to_string())
+}
+
+/// Makes sure we have a signed prekey and lets the swarm hand it out.
+async fn publish_prekeys(app: &tauri::AppHandle) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+
+    let prekey = match load_prekey(&storage_key).map_err(|e| e.to_string())? {
+        Some(prekey) => prekey,
+        None => {
+            let prekey = generate_prekey(&state.identity)?;
+            save_prekey(&prekey, &storage_key).map_err(|e| e.to_string())?;
+            prekey
+        }
+    };
+
+    let _ = state.tx.send(P2PCommand::PublishPreKeys { bundle: prekey.bundle }).await;
+    Ok(())
+}
+
+
+/// Records an incoming attachment and starts pulling it from the sender.
+async fn register_download(app: &tauri::AppHandle, peer: PeerId, attachment: Attachment) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let max_size = Config::load().map_err(|e| e.to_string())?.files.max_file_size;
+
+    if attachment.size > max_size {
+        return Err(format!("file is {} bytes, the limit is {max_size}", attachment.size));
+    }
+    // It names a file on disk and the sender chose it
+    if !is_valid_file_id(&attachment.file_id) {
+        return Err("invalid file id".into());
+    }
+
+    let record = FileRecord { attachment, peer, outgoing: false, complete: false, scoped: true };
+    update_file_index(&storage_key, |records| {
+        if !records.iter().any(|r| !r.outgoing && r.peer == peer && r.attachment.file_id == record.attachment.file_id) {
+            records.push(record.clone());
+        }
+    }).map_err(|e| e.to_string())?;
+
+    continue_download(app, &record).await
+}
+
+/// Asks for the next missing chunk, or verifies the file once it is all here.
+async fn continue_download(app: &tauri::AppHandle, record: &FileRecord) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let attachment = &record.attachment;
+    let local_id = record.local_id();
+    let offset = downloaded_bytes(&local_id).map_err(|e| e.to_string())?;
+
+    if offset < attachment.size {
+        let _ = app.state::<AppState>().tx.send(P2PCommand::RequestFileChunk {
+            peer: record.peer,
+            file_id: attachment.file_id.clone(),
+            offset,
+        }).await;
+        return Ok(());
+    }
+
+    finalize_download(&local_id, attachment, &storage_key).map_err(|e| e.to_string())?;
+    update_file_index(&storage_key, |records| {
+        if let Some(r) = records.iter_mut().find(|r| !r.outgoing && r.peer == record.peer && r.attachment.file_id == attachment.file_id) {
+            r.complete = true;
+        }
+    }).map_err(|e| e.to_string())?;
+
+    app.emit("file-received", (record.peer.to_string(), attachment.file_id.clone())).ok();
+    Ok(())
+}
+
+/// Answers a chunk request, only for files we sent to that same peer.
+fn serve_file_chunk(app: &tauri::AppHandle, peer: PeerId, file_id: &str, offset: u64) -> Result<FileResponse, String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+
+    // Every device of the contact got the message, any of them may fetch the file
+    let devices = load_devices(&storage_key).map_err(|e| e.to_string())?;
+    let contact = devices.account_of(&peer);
+    let record = load_file_index(&storage_key)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|r| r.outgoing && devices.account_of(&r.peer) == contact && r.attachment.file_id == file_id)
+        .ok_or("unknown file")?;
+
+    let chunk = read_chunk(&record.local_id(), offset, &storage_key).map_err(|e| e.to_string())?;
+    let (nonce, data) = seal_chunk(&record.attachment, offset, &chunk)?;
+
+    let sent = (offset + chunk.len() as u64).min(record.attachment.size);
+    app.emit("file-progress", (peer.to_string(), file_id, sent, record.attachment.size)).ok();
+
+    Ok(FileResponse::Chunk { file_id: file_id.to_string(), offset, nonce, data })
+}
+
+async fn on_file_chunk(app: &tauri::AppHandle, peer: PeerId, file_id: &str, offset: u64, nonce: [u8; 12], data: Vec<u8>) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+
+    let record = load_file_index(&storage_key)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|r| !r.outgoing && !r.complete && r.peer == peer && r.attachment.file_id == file_id)
+        .ok_or("chunk for a file we are not downloading")?;
+
+    // A late duplicate of a chunk we already wrote
+    if downloaded_bytes(&record.local_id()).map_err(|e| e.to_string())? != offset {
+        return Ok(());
+    }
+
+    let chunk = open_chunk(&record.attachment, offset, &nonce, &data)?;
+    if chunk.is_empty() {
+        return Err("sender returned an empty chunk".into());
+    }
+    append_chunk(&record.local_id(), &chunk, &storage_key).map_err(|e| e.to_string())?;
+
+    let received = (offset + chunk.len() as u64).min(record.attachment.size);
+    app.emit("file-progress", (peer.to_string(), file_id, received, record.attachment.size)).ok();
+
+    continue_download(app, &record).await
+}
+
+/// Picks up downloads from `peer` that were cut off by a disconnect or restart.
+async fn resume_downloads(app: &tauri::AppHandle, peer: PeerId) {
+    let Some(storage_key) = current_storage_key(app) else {
         return;
     };
 
+    let records = load_file_index(&storage_key).unwrap_or_default();
+    for record in records.iter().filter(|r| !r.outgoing && !r.complete && r.peer == peer) {
+        if let Err(e) = continue_download(app, record).await {
+            warn!("cannot resume {} from {peer}: {e}", record.attachment.file_id);
+        }
+    }
+}
 
-    let mut chats = load_chat(
-        &peer.to_string(),
-        storage_key,
-        chat_dir(),
-    ).unwrap_or_default();
+/// Snapshots the peer store to disk, pruning peers past the configured TTL.
+async fn persist_peers(app: &tauri::AppHandle) {
+    let Some(state) = app.try_state::<AppState>() else {
+        return;
+    };
+    let ttl_days = Config::load()
+        .map(|cfg| cfg.network.peer_ttl_days)
+        .unwrap_or_else(|_| Config::default().network.peer_ttl_days);
 
-    chats.push(msg.clone());
+    if let Err(e) = save_peers_to_disk(app, state.peer_store.clone(), ttl_days).await {
+        warn!("failed to save peer store: {e}");
+    }
+}
+
+/// Saves `message` locally and queues it for every device of `peer`'s account.
+async fn queue_chat(app: &tauri::AppHandle, peer: PeerId, message: ChatMessage) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+    let local = app.state::<AppState>().identity.public().to_peer_id();
+
+    let devices = load_devices(&storage_key).map_err(|e| e.to_string())?;
+    let contact = devices.account_of(&peer);
+    if is_blocked(&storage_key, contact) {
+        return Err("Contact is blocked".into());
+    }
+    let own_devices = devices.devices_of(&devices.local_account(&local));
 
-    save_chat(
-        peer.to_string(),
-        &chats,
-        storage_key,
-        chat_dir(),
-    ).ok();
+    for device in devices.devices_of(&contact).into_iter().filter(|d| !own_devices.contains(d)) {
+        // Each device has its own session, so each gets its own copy
+        let copy = ChatMessage { to: device, ..message.clone() };
 
-    app.emit("message-received", (peer.to_string(), msg)).ok();
+        // Keep it in the outbox until the device acks it, so it survives going offline or a restart
+        enqueue_outbox(
+            &device.to_string(),
+            copy.clone(),
+            &storage_key,
+        ).map_err(|e| e.to_string())?;
+
+        if let Err(e) = dispatch_chat(app, device, &copy).await {
+            warn!("message {} stays queued for {device}: {e}", message.id);
+        }
+    }
+    log::info!("send message");
+
+    store_chat(app, &contact.to_string(), &message.id, &message, &storage_key)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
 }
 
 
 fn start(app: &tauri::AppHandle) {
     let cfg = Config::load().unwrap();
+    let peer_ttl_days = cfg.network.peer_ttl_days;
+    let bootstrap_interval = Duration::from_secs(cfg.network.bootstrap_interval_secs);
+    let min_peers = cfg.network.min_connected_peers;
     let local_key = load_or_create_identity();
     let entry = Entry::new(SERVICE, KEY_NAME).unwrap();
     // let storage_key = derive_storage_key(&local_key);
@@ -123,11 +869,39 @@ fn start(app: &tauri::AppHandle) {
     let ctx = P2PService{event_tx};
 
 
+    // Loaded before anything can save, an early save would wipe the file
     let peer_store = Arc::new(PeerStore {
-        peers: RwLock::new(HashMap::new()),
+        peers: RwLock::new(load_peers_from_disk(app, peer_ttl_days)),
     });
 
-    app.manage(AppState {identity: local_key.clone(), entry: entry, tx, peer_store: peer_store.clone() });
+    recover_interrupted_rekey(&entry);
+
+    app.manage(AppState {identity: local_key.clone(), entry: entry, tx, peer_store: peer_store.clone(), in_flight: Mutex::new(HashSet::new()) });
+    app.manage(CredentialState {
+        storage_key: StdRwLock::new(None),
+        last_activity: Mutex::new(Instant::now()),
+        locked_inbox: Mutex::new(VecDeque::new()),
+    });
+
+    let app_handle = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let mut save_tick = tokio::time::interval(PEER_SAVE_INTERVAL);
+        // The first tick fires at once, there is nothing new to save yet
+        save_tick.tick().await;
+        loop {
+            save_tick.tick().await;
+            persist_peers(&app_handle).await;
+        }
+    });
+
+    let app_handle = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let mut idle_tick = tokio::time::interval(IDLE_CHECK);
+        loop {
+            idle_tick.tick().await;
+            lock_if_idle(&app_handle);
+        }
+    });
 
     let app_handle = app.clone();
     tauri::async_runtime::spawn(async move {
@@ -136,41 +910,158 @@ fn start(app: &tauri::AppHandle) {
                 P2PEvent::PeerDiscovered(peer) => {
                     app_handle.emit("peer-discovered", peer.to_string()).ok();
                 }
-                P2PEvent::MessageReceived { peer, msg } => {
-                   on_message_received(&app_handle, peer, msg).await;
+                P2PEvent::MessageReceived { peer, envelope, channel } => {
+                    let id = envelope.id.clone();
+                    let response = match on_message_received(&app_handle, peer, envelope).await {
+                        Ok(()) => GreetResponse::Delivered { id },
+                        Err(reason) => {
+                            warn!("deferring message {id} from {peer}: {reason}");
+                            GreetResponse::Deferred { id, reason }
+                        }
+                    };
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let _ = app_handle.state::<AppState>().tx.send(P2PCommand::RespondChat { channel, response }).await;
+                    });
+                }
+                P2PEvent::MessageStatus { peer, id, status } => {
+                    if status != DeliveryStatus::Pending {
+                        app_handle.state::<AppState>().in_flight.lock().unwrap().remove(&(peer, id.clone()));
+                    }
+                    if matches!(status, DeliveryStatus::Delivered | DeliveryStatus::Rejected) {
+                        if let Some(storage_key) = current_storage_key(&app_handle) {
+                            if let Err(e) = remove_from_outbox(&peer.to_string(), &id, &storage_key) {
+                                warn!("failed to remove {id} from outbox: {e}");
+                            }
+                        }
+                    }
+                    app_handle.emit("message-status", (peer.to_string(), id, status)).ok();
+                }
+                P2PEvent::SecurityWarning { peer, reason } => {
+                    app_handle.emit("security-warning", (peer.to_string(), reason)).ok();
+                }
+                P2PEvent::PreKeyBundle { peer, bundle } => {
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        match start_session(&app_handle, peer, bundle).await {
+                            Ok(()) => flush_outbox(&app_handle, peer).await,
+                            Err(e) => warn!("failed to start session with {peer}: {e}"),
+                        }
+                    });
+                }
+                P2PEvent::GroupInvite { peer, invite } => {
+                    if current_storage_key(&app_handle).is_none() {
+                        hold_while_locked(&app_handle, LockedInbound::GroupInvite { peer, invite });
+                    } else if let Err(e) = on_group_invite(&app_handle, peer, invite).await {
+                        warn!("ignoring group invite from {peer}: {e}");
+                    }
+                }
+                P2PEvent::GroupMessageReceived { envelope } => {
+                    let id = envelope.id.clone();
+                    if current_storage_key(&app_handle).is_none() {
+                        hold_while_locked(&app_handle, LockedInbound::GroupMessage { envelope });
+                    } else if let Err(e) = on_group_message(&app_handle, envelope).await {
+                        warn!("dropping group message {id}: {e}");
+                    }
+                }
+                P2PEvent::PeerConnected(peer) => {
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        announce_devices(&app_handle, peer).await;
+                        flush_outbox(&app_handle, peer).await;
+                        resume_downloads(&app_handle, peer).await;
+                    });
+                }
+                P2PEvent::FileChunkRequested { peer, file_id, offset, channel } => {
+                    let response = serve_file_chunk(&app_handle, peer, &file_id, offset)
+                        .unwrap_or_else(|reason| {
+                            warn!("refusing chunk {offset} of {file_id} to {peer}: {reason}");
+                            FileResponse::Denied { file_id, reason }
+                        });
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let _ = app_handle.state::<AppState>().tx.send(P2PCommand::RespondFile { channel, response }).await;
+                    });
+                }
+                P2PEvent::FileChunkReceived { peer, response } => {
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let (file_id, result) = match response {
+                            FileResponse::Chunk { file_id, offset, nonce, data } => {
+                                let result = on_file_chunk(&app_handle, peer, &file_id, offset, nonce, data).await;
+                                (file_id, result)
+                            }
+                            FileResponse::Denied { file_id, reason } => (file_id, Err(reason)),
+                        };
+                        if let Err(e) = result {
+                            warn!("file {file_id} from {peer} failed: {e}");
+                            app_handle.emit("file-failed", (peer.to_string(), file_id, e)).ok();
+                        }
+                    });
+                }
+                P2PEvent::FileTransferFailed { peer, file_id, reason } => {
+                    app_handle.emit("file-failed", (peer.to_string(), file_id, reason)).ok();
+                }
+                P2PEvent::DeviceLinkRequested { peer, consent } => {
+                    if let Err(e) = on_link_request(&app_handle, peer, consent) {
+                        warn!("ignoring link request from {peer}: {e}");
+                    }
+                }
+                P2PEvent::DeviceList { peer, account, certs } => {
+                    if let Err(e) = on_device_list(&app_handle, peer, account, certs).await {
+                        warn!("ignoring device list from {peer}: {e}");
+                    }
+                }
+                P2PEvent::NetworkHealth(health) => {
+                    app_handle.emit("network-health", health).ok();
                 }
             }
         }
     });
 
-    let app_handle = app.clone();
     tauri::async_runtime::spawn(async move {
         let mut swarm = p2p.create_p2p().await.unwrap();
-        let stored_peers = load_peers_from_disk(&app_handle);
-        let mut to_add = Vec::new();
-
-        {
-            let mut peers = peer_store.peers.write().await;
-            for (peer_id, peer) in stored_peers {
-                for addr in &peer.addrs {
-                    if let Ok(addr) = addr.parse() {
-                        to_add.push((peer_id, addr));
-                    }
+        let mut agent = Agent::new();
+        agent.bootstrap_dials = p2p.dial_bootstrap_nodes(&mut swarm);
+
+        // Best reputation first, peers that keep failing wait out their backoff
+        for (i, (peer_id, addrs)) in peer_store.dial_order().await.into_iter().enumerate() {
+            let addrs: Vec<Multiaddr> = addrs.iter().filter_map(|a| a.parse().ok()).collect();
+            for addr in &addrs {
+                swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+            }
+            if i < MAX_STARTUP_DIALS {
+                if let Err(e) = swarm.dial(DialOpts::peer_id(peer_id).addresses(addrs).build()) {
+                    warn!("failed to dial stored peer {peer_id}: {e}");
                 }
-                peers.insert(peer_id, peer);
             }
         }
-        for (peer_id, addr) in to_add {
-            swarm.behaviour_mut().kad.add_address(&peer_id, addr);
-        }
+
+        let mut retry_tick = tokio::time::interval(RETRY_TICK);
+        let mut health_tick = tokio::time::interval(HEALTH_CHECK);
+        let mut bootstrap_tick = tokio::time::interval_at(tokio::time::Instant::now() + bootstrap_interval, bootstrap_interval);
         
         loop {
             tokio::select! {
                 event = swarm.select_next_some() => {
-                    handle_swarm_event(event, &mut swarm, &peer_store, &ctx.event_tx).await;
+                    handle_swarm_event(event, &mut swarm, &mut agent, &peer_store, &ctx.event_tx).await;
                 }
                 Some(cmd) = rx.recv() => {
-                    handle_command(cmd, &mut swarm);
+                    handle_command(cmd, &mut swarm, &mut agent, &ctx.event_tx).await;
+                }
+                _ = retry_tick.tick() => {
+                    retry_pending_chats(&mut swarm, &mut agent);
+                }
+                _ = bootstrap_tick.tick() => {
+                    refresh_routing(&mut swarm, &mut agent, &p2p);
+                }
+                _ = health_tick.tick() => {
+                    let health = network_health(&mut swarm, &agent, min_peers);
+                    // Re-bootstrapping is left to `bootstrap_tick`
+                    if health.status != HealthStatus::Healthy {
+                        redial_bootstrap(&mut swarm, &mut agent, &p2p);
+                    }
+                    let _ = ctx.event_tx.send(P2PEvent::NetworkHealth(health)).await;
                 }
             }
         }
@@ -209,17 +1100,22 @@ async fn send_greet(state: tauri::State<'_, AppState>, name: String) -> Result<S
 }
 
 #[tauri::command]
-async fn find_peer(state: tauri::State<'_, AppState>, peer_id: String) -> Result<Vec<PeerId>, String> {
+async fn find_peer(state: tauri::State<'_, AppState>, peer_id: String) -> Result<Vec<DiscoveredPeer>, String> {
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let (reply, rx) = oneshot::channel();
+
     state
         .tx
-        .send(P2PCommand::FindNode {
-            peer: peer_id.parse::<PeerId>().unwrap(),
-        })
+        .send(P2PCommand::FindNode { peer, reply })
         .await
-        .unwrap();
-    // log::info!("send_greet");
-    log::info!("find peer: {:?}", state.peer_store.peers.read().await.keys().cloned().collect::<Vec<PeerId>>());
-    Ok(state.peer_store.peers.read().await.keys().cloned().collect())
+        .map_err(|_| "network is not running")?;
+
+    // Kademlia times the query out itself, this only covers a stuck swarm
+    match tokio::time::timeout(KAD_QUERY_TIMEOUT + Duration::from_secs(5), rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("network stopped before the lookup finished".into()),
+        Err(_) => Err("peer lookup timed out".into()),
+    }
 }
 
 #[tauri::command]
@@ -228,61 +1124,545 @@ async fn send_message(
     peer_id: String,
     message: ChatMessage,
 ) -> Result<(), String> {
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    touch_activity(&app);
+    queue_chat(&app, peer, message).await
+}
+
+#[tauri::command]
+async fn send_file(app: tauri::AppHandle, peer_id: String, path: String) -> Result<ChatMessage, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    touch_activity(&app);
+    let max_size = Config::load().map_err(|e| e.to_string())?.files.max_file_size;
+
+    let attachment = import_file(&PathBuf::from(path), max_size, &storage_key)
+        .map_err(|e| e.to_string())?;
+
+    let record = FileRecord { attachment: attachment.clone(), peer, outgoing: true, complete: true, scoped: false };
+    update_file_index(&storage_key, |records| records.push(record))
+        .map_err(|e| e.to_string())?;
+
+    let message = ChatMessage {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        from: app.state::<AppState>().identity.public().to_peer_id(),
+        to: peer,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        content: attachment.name.clone(),
+        attachment: Some(attachment),
+    };
+
+    queue_chat(&app, peer, message.clone()).await?;
+    Ok(message)
+}
+
+/// `peer_id` is who the file was sent to or received from, ids are only unique per peer.
+#[tauri::command]
+fn save_attachment(app: tauri::AppHandle, peer_id: String, file_id: String, dest_path: String) -> Result<(), String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+
+    let record = load_file_index(&storage_key)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|r| r.peer == peer && r.attachment.file_id == file_id && r.complete)
+        .ok_or("File not downloaded yet")?;
+
+    export_file(&record.local_id(), &PathBuf::from(dest_path), &storage_key).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn setup_password(app: tauri::AppHandle, state: tauri::State<'_, AppState>, mut password: String) -> Result<(), String> {
+    let storage_key = generate_storage_key();
+
+    let wrapped = wrap_storage_key(&storage_key, &password, &kdf_params());
+    password.zeroize();
+    let wrapped = wrapped.map_err(|e| e.to_string())?;
+    save_wrapped_key(&wrapped, &state.entry).map_err(|e| e.to_string())?;
+    set_storage_key(&app, Some(storage_key));
+
+    let app_handle = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = publish_prekeys(&app_handle).await {
+            warn!("failed to publish prekeys: {e}");
+        }
+    });
+
+    Ok(())
+}
+
+#[tauri::command]
+fn unlock_app(app: tauri::AppHandle, mut password: String) -> Result<(), String> {
+    let unlocked = unlock_storage_key(&app, &password);
+    password.zeroize();
+    set_storage_key(&app, Some(unlocked?));
+
+    let app_handle = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = publish_prekeys(&app_handle).await {
+            warn!("failed to publish prekeys: {e}");
+        }
+        if let Err(e) = apply_blocklist(&app_handle).await {
+            warn!("failed to apply blocklist: {e}");
+        }
+        drain_locked_inbox(&app_handle).await;
+        if let Err(e) = check_chats(&app_handle) {
+            warn!("chat history check failed: {e}");
+        }
+        if let Err(e) = subscribe_joined_groups(&app_handle).await {
+            warn!("failed to rejoin groups: {e}");
+        }
+        for peer in outbox_peers(outbox_dir()) {
+            if let Ok(peer) = peer.parse::<PeerId>() {
+                flush_outbox(&app_handle, peer).await;
+            }
+        }
+    });
+
+    app.emit("app-ready", ()).ok();
+    Ok(())
+}
+
+#[tauri::command]
+fn lock_app(app: tauri::AppHandle) -> Result<(), String> {
+    lock_storage(&app, "manual");
+    Ok(())
+}
+
+/// Called by the frontend on user input, keeps the idle timer from locking.
+#[tauri::command]
+fn report_activity(app: tauri::AppHandle) -> Result<(), String> {
+    touch_activity(&app);
+    Ok(())
+}
+
+/// Whether the frontend has to ask for the password before anything else works.
+#[tauri::command]
+fn is_locked(app: tauri::AppHandle) -> bool {
+    current_storage_key(&app).is_none()
+}
+
+/// Key derivation settings for new wraps, the defaults if the config is unreadable.
+fn kdf_params() -> KdfParams {
+    Config::load().map(|cfg| cfg.security.kdf).unwrap_or_default()
+}
+
+/// Recovers the storage key by unwrapping it with `password`. A raw key left
+/// in the keyring by older versions is replaced by a fresh, wrapped one.
+fn unlock_storage_key(app: &tauri::AppHandle, password: &str) -> Result<[u8; 32], String> {
+    let state = app.state::<AppState>();
+
+    match load_stored_key(&state.entry).ok_or("Storage key not found")? {
+        StoredKey::Wrapped(wrapped) => {
+            let storage_key = unwrap_storage_key(&wrapped, password).map_err(|_| "Invalid password")?;
+
+            // Only now do we have the password to derive with better parameters
+            let target = kdf_params();
+            if wrapped.kdf.should_upgrade_to(&target) {
+                let upgraded = wrap_storage_key(&storage_key, password, &target)
+                    .map_err(|e| e.to_string())
+                    .and_then(|w| save_wrapped_key(&w, &state.entry).map_err(|e| e.to_string()));
+                match upgraded {
+                    Ok(()) => log::info!("storage key re-wrapped with {target:?}"),
+                    Err(e) => warn!("failed to upgrade key derivation parameters: {e}"),
+                }
+            }
+            Ok(storage_key)
+        }
+        StoredKey::Legacy(raw) => {
+            let salt = load_salt_from_disk(app)
+                .ok_or("Salt not found. App corrupted?")?;
+            let derived = derive_storage_key(password, &salt, &KdfParams::legacy()).map_err(|e| e.to_string())?;
+            if derived != raw {
+                return Err("Invalid password".into());
+            }
+
+            // Anyone who read the keyring had this key, so nothing stays under it
+            let storage_key = generate_storage_key();
+            let wrapped = wrap_storage_key(&storage_key, password, &kdf_params()).map_err(|e| e.to_string())?;
+            rekey_storage(&raw, &storage_key, &wrapped, &state.entry)?;
+            remove_salt_from_disk(app);
+            Ok(storage_key)
+        }
+    }
+}
+
+/// Re-encrypts all local state under a new storage key wrapped by `new_password`.
+#[tauri::command]
+async fn change_password(app: tauri::AppHandle, mut old_password: String, mut new_password: String) -> Result<(), String> {
+    current_storage_key(&app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+    let verified = unlock_storage_key(&app, &old_password);
+    old_password.zeroize();
+    let verified = Zeroizing::new(verified?);
 
+    let new_key = Zeroizing::new(generate_storage_key());
+    let wrapped = wrap_storage_key(&new_key, &new_password, &kdf_params());
+    new_password.zeroize();
+    let wrapped = wrapped.map_err(|e| e.to_string())?;
+
+    // Nothing may read or write encrypted state while it is being swapped out,
+    // writes still holding the old key fail in `key_in_use` afterwards
+    let _sessions = SESSION_LOCK.lock().await;
     let cred = app.state::<CredentialState>();
-    let message_state = app.state::<AppState>();
 
-    let mut chats = load_chat(
-        &peer_id,
-        cred.storage_key.as_ref().ok_or("App locked")?,
-       chat_dir(),
-    ).unwrap_or_default();  
+    replace_current_key(&new_key, || {
+        let mut storage_key = cred.storage_key.write().unwrap();
+        let old_key = Zeroizing::new(storage_key.ok_or("App locked")?);
+        if old_key != verified {
+            return Err("Invalid password".into());
+        }
 
-    let _ = message_state.tx.send(P2PCommand::SendChat { peer: peer_id.clone().parse::<PeerId>().unwrap(), msg: message.clone() }).await;
-    log::info!("send message");
-    chats.push(message);
+        rekey_storage(&old_key, &new_key, &wrapped, &state.entry)?;
+        *storage_key = Some(*new_key);
+        Ok(())
+    })
+}
+
+/// Writes identity, peers, config, groups and every history to one archive
+/// encrypted under `password`.
+#[tauri::command]
+async fn export_backup(app: tauri::AppHandle, dest_path: String, mut password: String) -> Result<(), String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    touch_activity(&app);
+    let state = app.state::<AppState>();
+
+    let peers = state.peer_store.peers.read().await
+        .iter()
+        .map(|(id, peer)| (id.to_string(), peer.clone()))
+        .collect();
+
+    let mut chats = Vec::new();
+    for name in chat_names(chat_dir()) {
+        let messages = load_chat(&name, &storage_key, chat_dir())
+            .map_err(|e| format!("chat {name} cannot be backed up: {e}"))?;
+        chats.push(BackupChat { name, messages });
+    }
+
+    let mut sessions = HashMap::new();
+    {
+        let _guard = SESSION_LOCK.lock().await;
+        for peer in session_peers(session_dir()) {
+            let peer_sessions = load_sessions(&peer, &storage_key, session_dir())
+                .map_err(|e| format!("sessions with {peer} cannot be backed up: {e}"))?;
+            sessions.insert(peer, peer_sessions);
+        }
+    }
+
+    let payload = BackupPayload {
+        identity: export_identity(&state.identity)?,
+        peers,
+        config: Config::load().map_err(|e| e.to_string())?,
+        groups: load_groups(&storage_key).map_err(|e| e.to_string())?,
+        devices: load_devices(&storage_key).map_err(|e| e.to_string())?,
+        contacts: load_contacts(&storage_key).map_err(|e| e.to_string())?,
+        chats,
+        sessions,
+        prekey: load_prekey(&storage_key).map_err(|e| e.to_string())?,
+        files: load_file_index(&storage_key).map_err(|e| e.to_string())?,
+    };
+
+    let written = write_backup(&PathBuf::from(dest_path), &payload, &password, &kdf_params(), &storage_key);
+    password.zeroize();
+    written.map_err(|e| e.to_string())
+}
+
+/// Restores an archive from `export_backup` on an install without a password
+/// yet, protecting it with `password`. The app restarts to load the identity.
+#[tauri::command]
+async fn import_backup(app: tauri::AppHandle, src_path: String, mut backup_password: String, mut password: String) -> Result<(), String> {
+    let state = app.state::<AppState>();
+    if load_stored_key(&state.entry).is_some() {
+        backup_password.zeroize();
+        password.zeroize();
+        return Err("Backups can only be restored on a fresh install".into());
+    }
+
+    let payload = read_backup(&PathBuf::from(src_path), &backup_password).map_err(|e| e.to_string());
+    backup_password.zeroize();
+    let (payload, mut files) = match payload {
+        Ok(read) => read,
+        Err(e) => {
+            password.zeroize();
+            return Err(e);
+        }
+    };
+    payload.config.validate()?;
+
+    let storage_key = generate_storage_key();
+    let wrapped = wrap_storage_key(&storage_key, &password, &payload.config.security.kdf);
+    password.zeroize();
+    let wrapped = wrapped.map_err(|e| e.to_string())?;
+    set_current_key(&storage_key);
+
+    // Written files are unreadable until the key is saved below, so a failed
+    // import leaves the install fresh and can simply be retried
+    for chat in &payload.chats {
+        restore_chat(&chat.name, &chat.messages, &storage_key, chat_dir())
+            .map_err(|e| format!("failed to restore chat {}: {e}", chat.name))?;
+        forget_chat(&chat.name, &storage_key).map_err(|e| e.to_string())?;
+    }
+    save_groups(&payload.groups, &storage_key).map_err(|e| e.to_string())?;
+    save_devices(&payload.devices, &storage_key).map_err(|e| e.to_string())?;
+    save_contacts(&payload.contacts, &storage_key).map_err(|e| e.to_string())?;
+    for (peer, sessions) in &payload.sessions {
+        save_sessions(peer, sessions, &storage_key, session_dir()).map_err(|e| e.to_string())?;
+    }
+    if let Some(prekey) = &payload.prekey {
+        save_prekey(prekey, &storage_key).map_err(|e| e.to_string())?;
+    }
+    // Unfinished downloads come back without data and are fetched again
+    restore_files(&mut files, &payload.files, &storage_key).map_err(|e| e.to_string())?;
+    save_file_index(&payload.files, &storage_key).map_err(|e| e.to_string())?;
+
+    {
+        let mut peers = state.peer_store.peers.write().await;
+        for (peer_id, peer) in payload.peers {
+            if let Ok(peer_id) = peer_id.parse::<PeerId>() {
+                peers.insert(peer_id, peer);
+            }
+        }
+    }
+    save_peers_to_disk(&app, state.peer_store.clone(), payload.config.network.peer_ttl_days)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    payload.config.save().map_err(|e| e.to_string())?;
+    import_identity(&payload.identity)?;
+    save_wrapped_key(&wrapped, &state.entry).map_err(|e| e.to_string())?;
+
+    log::info!("restored backup with {} chat(s)", payload.chats.len());
+    app.request_restart();
+    Ok(())
+}
+
+#[tauri::command]
+fn list_contacts(app: tauri::AppHandle) -> Result<Vec<Contact>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    load_contacts(&storage_key).map_err(|e| e.to_string())
+}
+
+/// Adds `peer_id` as a contact or updates its details, keeping the
+/// verified and blocked flags. A linked device is saved as its account.
+#[tauri::command]
+fn save_contact(app: tauri::AppHandle, peer_id: String, name: String, notes: String, avatar_hash: Option<String>) -> Result<Contact, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let account = contact_of(&storage_key, peer);
+
+    update_contacts(&storage_key, |contacts| {
+        let contact = contact_entry(contacts, account);
+        contact.name = name;
+        contact.notes = notes;
+        contact.avatar_hash = avatar_hash;
+        contact.clone()
+    }).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_contact(app: tauri::AppHandle, peer_id: String) -> Result<(), String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let account = contact_of(&storage_key, peer);
+
+    update_contacts(&storage_key, |contacts| contacts.retain(|c| c.peer_id != account))
+        .map_err(|e| e.to_string())?;
 
-    save_chat(
-        peer_id.clone(),
-        &chats,
-        cred.storage_key.as_ref().ok_or("App locked")?,
-        chat_dir(),
-    ).map_err(|e| e.to_string())
+    // Without an entry nothing remembers the block
+    set_swarm_blocked(&app, &storage_key, account, false).await
 }
 
 #[tauri::command]
-fn setup_password(app: tauri::AppHandle, state: tauri::State<'_, AppState>, password: String) -> Result<(), String> {
-    let salt = rand::random::<[u8; 16]>();
+async fn set_contact_blocked(app: tauri::AppHandle, peer_id: String, blocked: bool) -> Result<Contact, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let account = contact_of(&storage_key, peer);
+
+    let contact = update_contacts(&storage_key, |contacts| {
+        let contact = contact_entry(contacts, account);
+        contact.blocked = blocked;
+        contact.clone()
+    }).map_err(|e| e.to_string())?;
+
+    set_swarm_blocked(&app, &storage_key, account, blocked).await?;
+    Ok(contact)
+}
+
+#[tauri::command]
+fn set_contact_verified(app: tauri::AppHandle, peer_id: String, verified: bool) -> Result<Contact, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let account = contact_of(&storage_key, peer);
+
+    let fingerprint = match verified {
+        true => Some(hex::encode(fingerprint_of(&storage_key, account)?)),
+        false => None,
+    };
+
+    update_contacts(&storage_key, |contacts| {
+        let contact = contact_entry(contacts, account);
+        contact.verified = verified;
+        contact.verified_fingerprint = fingerprint;
+        contact.clone()
+    }).map_err(|e| e.to_string())
+}
+
+/// Safety number between our account and `peer_id`'s, to compare in person.
+#[tauri::command]
+fn get_safety_number(app: tauri::AppHandle, peer_id: String) -> Result<SafetyNumber, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    contact_safety_number(&app, &storage_key, contact_of(&storage_key, peer))
+}
+
+/// Checks digits read out by the contact, or their scanned QR code, and
+/// marks them verified if it matches.
+#[tauri::command]
+fn verify_safety_number(app: tauri::AppHandle, peer_id: String, input: String) -> Result<bool, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let account = contact_of(&storage_key, peer);
+
+    if !matches_safety_number(&contact_safety_number(&app, &storage_key, account)?, &input) {
+        return Ok(false);
+    }
 
-    let storage_key = derive_storage_key(&password, &salt);
+    let fingerprint = hex::encode(fingerprint_of(&storage_key, account)?);
+    update_contacts(&storage_key, |contacts| {
+        let contact = contact_entry(contacts, account);
+        contact.verified = true;
+        contact.verified_fingerprint = Some(fingerprint);
+    }).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+fn contact_safety_number(app: &tauri::AppHandle, storage_key: &[u8; 32], account: PeerId) -> Result<SafetyNumber, String> {
+    let local = app.state::<AppState>().identity.public().to_peer_id();
+    let devices = load_devices(storage_key).map_err(|e| e.to_string())?;
+    let local_account = devices.local_account(&local);
+
+    let ours = account_fingerprint(&local_account, &devices.devices_of(&local_account))?;
+    let theirs = account_fingerprint(&account, &devices.devices_of(&account))?;
+    Ok(safety_number(&ours, &theirs))
+}
 
-    save_storage_key(&storage_key, &state.entry);
-    let _ = save_salt_to_disk(&app, &salt);
-    app.manage(CredentialState{storage_key: Some(storage_key)});
+async fn set_swarm_blocked(app: &tauri::AppHandle, storage_key: &[u8; 32], account: PeerId, blocked: bool) -> Result<(), String> {
+    let state = app.state::<AppState>();
+    let devices = load_devices(storage_key).map_err(|e| e.to_string())?;
 
+    for peer in devices.devices_of(&account) {
+        let cmd = if blocked { P2PCommand::BlockPeer { peer } } else { P2PCommand::UnblockPeer { peer } };
+        let _ = state.tx.send(cmd).await;
+    }
     Ok(())
 }
 
+/// Asks the primary device of `account_id` to take this install into its account.
 #[tauri::command]
-fn unlock_app(app: tauri::AppHandle, state: tauri::State<'_, AppState>, password: String) -> Result<(), String> {
-    let salt = load_salt_from_disk(&app)
-        .ok_or("Salt not found. App corrupted?")?;
+async fn request_device_link(app: tauri::AppHandle, account_id: String) -> Result<(), String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+    let account = account_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let local = state.identity.public().to_peer_id();
+
+    update_devices(&storage_key, |store| {
+        if store.accounts.get(&local).is_some_and(|certs| !certs.is_empty()) {
+            return Err("This device is the primary of its own account");
+        }
+        store.pending_link = Some(account);
+        Ok(())
+    }).map_err(|e| e.to_string())??;
+
+    let consent = consent_to_link(&state.identity, account)?;
+    let _ = state.tx.send(P2PCommand::RequestDeviceLink { peer: account, consent }).await;
+    Ok(())
+}
 
-    let derived = derive_storage_key(&password, &salt);
+/// Certifies `device_id` as one of our devices and sends every device the new list.
+#[tauri::command]
+async fn approve_device(app: tauri::AppHandle, device_id: String) -> Result<DeviceCertificate, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+    let device = device_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let local = state.identity.public().to_peer_id();
 
-    let stored = load_storage_key(&state.entry)
-        .ok_or("Storage key not found")?;
+    let (cert, certs) = update_devices(&storage_key, |store| {
+        if store.own.is_some() {
+            return Err("Only the primary device can link devices".to_string());
+        }
+        let consent = store.link_requests.remove(&device).ok_or("This device has not asked to join")?;
+        let cert = issue_certificate(&state.identity, device, consent)?;
+        let certs = store.accounts.entry(local).or_default();
+        certs.retain(|c| c.device != device);
+        certs.push(cert.clone());
+        Ok((cert, certs.clone()))
+    }).map_err(|e| e.to_string())??;
 
-    if derived != stored {
-        return Err("Invalid password".into());
+    for cert in &certs {
+        let _ = state.tx.send(P2PCommand::SendDeviceList { peer: cert.device, certs: certs.clone() }).await;
     }
+    Ok(cert)
+}
 
-    app.manage(CredentialState{storage_key: Some(derived)});
+/// Drops `device_id` from our account. Contacts stop sending to it once this
+/// device, the primary, next announces its list to them.
+#[tauri::command]
+async fn unlink_device(app: tauri::AppHandle, device_id: String) -> Result<(), String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+    let device = device_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let local = state.identity.public().to_peer_id();
 
-    app.emit("app-ready", ()).ok();
+    let certs = update_devices(&storage_key, |store| {
+        if store.own.is_some() {
+            return Err("Only the primary device can unlink devices");
+        }
+        let certs = store.accounts.entry(local).or_default();
+        certs.retain(|c| c.device != device);
+        Ok(certs.clone())
+    }).map_err(|e| e.to_string())??;
+
+    for peer in certs.iter().map(|c| c.device).chain([device]) {
+        let _ = state.tx.send(P2PCommand::SendDeviceList { peer, certs: certs.clone() }).await;
+    }
     Ok(())
 }
 
+/// Moves `device_id` to the account that last claimed it, after another
+/// account already had it. Returns the new account.
+#[tauri::command]
+fn confirm_device_move(app: tauri::AppHandle, device_id: String) -> Result<String, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let device = device_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+
+    let (previous, account) = update_devices(&storage_key, |store| {
+        let previous = store.account_of(&device);
+        store.confirm_move(&device).map(|account| (previous, account))
+    }).map_err(|e| e.to_string())?
+        .ok_or("No other account claims this device")?;
+
+    // Both accounts' fingerprints change
+    check_contact_keys(&app, &storage_key, previous);
+    check_contact_keys(&app, &storage_key, account);
+    Ok(account.to_string())
+}
+
+/// Certificates of every device in our account besides the primary.
+#[tauri::command]
+fn list_devices(app: tauri::AppHandle) -> Result<Vec<DeviceCertificate>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let local = app.state::<AppState>().identity.public().to_peer_id();
+
+    let store = load_devices(&storage_key).map_err(|e| e.to_string())?;
+    Ok(store.accounts.get(&store.local_account(&local)).cloned().unwrap_or_default())
+}
+
+/// Reputation of every stored peer, best first, for the connection quality indicator.
+#[tauri::command]
+async fn get_peer_reputation(state: tauri::State<'_, AppState>) -> Result<Vec<PeerReputation>, String> {
+    Ok(state.peer_store.reputations().await)
+}
+
 #[tauri::command]
 fn get_self_peer_id(state: tauri::State<'_, AppState>) -> Result<String, String> {
     Ok(state.identity.public().to_peer_id().to_string())
@@ -290,25 +1670,191 @@ fn get_self_peer_id(state: tauri::State<'_, AppState>) -> Result<String, String>
 
 #[tauri::command]
 fn get_history_message(app: tauri::AppHandle, peer_id: String) -> Result<Vec<ChatMessage>, String> {
-    let cred = app.state::<CredentialState>();
-    let storage_key = cred.storage_key.as_ref().ok_or("App locked")?;
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
 
-    log::info!("storage key: {:?}", &storage_key);
     log::info!("peer id: {}", peer_id);
+    let name = history_name(&storage_key, &peer_id);
      let chats = load_chat(
-        &peer_id,
-        &*storage_key,
+        &name,
+        &storage_key,
        chat_dir(),
-    ).unwrap_or_default();  
+    ).unwrap_or_else(|e| {
+        on_chat_load_error(&app, &name, &e);
+        vec![]
+    });
     log::info!("get history message: {:?}", chats);
     Ok(chats)
 }
 
+#[tauri::command]
+fn get_history_page(app: tauri::AppHandle, peer_id: String, cursor: Option<HistoryCursor>, limit: usize) -> Result<Vec<ChatMessage>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+
+    let name = history_name(&storage_key, &peer_id);
+    load_chat_page(&name, cursor.as_ref(), limit.min(MAX_PAGE_SIZE), |m: &ChatMessage| m.timestamp, &storage_key, chat_dir())
+        .map_err(|e| {
+            on_chat_load_error(&app, &name, &e);
+            e.to_string()
+        })
+}
+
+#[tauri::command]
+fn search_messages(app: tauri::AppHandle, query: String, limit: usize) -> Result<Vec<SearchResult>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+
+    search_index(&query, limit.min(MAX_PAGE_SIZE), &storage_key).map_err(|e| e.to_string())
+}
+
+
+#[tauri::command]
+async fn create_group(app: tauri::AppHandle, name: String, members: Vec<String>) -> Result<GroupInfo, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+    let local = state.identity.public().to_peer_id();
+
+    let mut invitees = Vec::new();
+    for member in members {
+        let peer = member.parse::<PeerId>().map_err(|e| e.to_string())?;
+        if peer != local && !invitees.contains(&peer) {
+            invitees.push(peer);
+        }
+    }
+
+    let group = Group {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        name,
+        admin: local,
+        members: [vec![local], invitees.clone()].concat(),
+        key: rand::random::<[u8; 32]>(),
+        joined: true,
+    };
+
+    let mut groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+    groups.push(group.clone());
+    save_groups(&groups, &storage_key).map_err(|e| e.to_string())?;
+
+    let _ = state.tx.send(P2PCommand::JoinGroup { group_id: group.id.clone() }).await;
+    for peer in invitees {
+        let invite = wrap_group_key(&state.identity, &peer, &group)?;
+        let _ = state.tx.send(P2PCommand::SendGroupInvite { peer, invite }).await;
+    }
+
+    Ok(group.info())
+}
+
+#[tauri::command]
+async fn invite_to_group(app: tauri::AppHandle, group_id: String, peer_id: String) -> Result<(), String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    let state = app.state::<AppState>();
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+
+    let mut groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+    let group = groups
+        .iter_mut()
+        .find(|g| g.id == group_id)
+        .ok_or("Group not found")?;
+
+    if !group.members.contains(&peer) {
+        group.members.push(peer);
+    }
+    let invite = wrap_group_key(&state.identity, &peer, group)?;
+    save_groups(&groups, &storage_key).map_err(|e| e.to_string())?;
+
+    let _ = state.tx.send(P2PCommand::SendGroupInvite { peer, invite }).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn join_group(app: tauri::AppHandle, group_id: String) -> Result<(), String> {
+    set_group_joined(&app, &group_id, true)?;
+    let _ = app.state::<AppState>().tx.send(P2PCommand::JoinGroup { group_id }).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn leave_group(app: tauri::AppHandle, group_id: String) -> Result<(), String> {
+    set_group_joined(&app, &group_id, false)?;
+    let _ = app.state::<AppState>().tx.send(P2PCommand::LeaveGroup { group_id }).await;
+    Ok(())
+}
+
+fn set_group_joined(app: &tauri::AppHandle, group_id: &str, joined: bool) -> Result<(), String> {
+    let storage_key = current_storage_key(app).ok_or("App locked")?;
+
+    let mut groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+    let group = groups
+        .iter_mut()
+        .find(|g| g.id == group_id)
+        .ok_or("Group not found")?;
+    group.joined = joined;
+
+    save_groups(&groups, &storage_key).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_groups(app: tauri::AppHandle) -> Result<Vec<GroupInfo>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+
+    let groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+    Ok(groups.iter().map(Group::info).collect())
+}
+
+#[tauri::command]
+async fn send_group_message(app: tauri::AppHandle, group_id: String, content: String) -> Result<GroupMessage, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+    touch_activity(&app);
+    let state = app.state::<AppState>();
+
+    let groups = load_groups(&storage_key).map_err(|e| e.to_string())?;
+    let group = groups
+        .iter()
+        .find(|g| g.id == group_id && g.joined)
+        .ok_or("Not a member of this group")?;
+
+    let msg = GroupMessage {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        group_id: group.id.clone(),
+        from: state.identity.public().to_peer_id(),
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        content,
+    };
+
+    let envelope = seal_group_message(group, &msg)?;
+    let _ = state.tx.send(P2PCommand::PublishGroup { envelope }).await;
+
+    store_chat(&app, &group.history_name(), &msg.id, &msg, &storage_key)
+        .map_err(|e| e.to_string())?;
+
+    Ok(msg)
+}
+
+#[tauri::command]
+fn get_group_history(app: tauri::AppHandle, group_id: String) -> Result<Vec<GroupMessage>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+
+    let name = group_history_name(&group_id);
+    Ok(load_chat(&name, &storage_key, chat_dir()).unwrap_or_else(|e| {
+        on_chat_load_error(&app, &name, &e);
+        vec![]
+    }))
+}
+
+#[tauri::command]
+fn get_group_history_page(app: tauri::AppHandle, group_id: String, cursor: Option<HistoryCursor>, limit: usize) -> Result<Vec<GroupMessage>, String> {
+    let storage_key = current_storage_key(&app).ok_or("App locked")?;
+
+    let name = group_history_name(&group_id);
+    load_chat_page(&name, cursor.as_ref(), limit.min(MAX_PAGE_SIZE), |m: &GroupMessage| m.timestamp, &storage_key, chat_dir())
+        .map_err(|e| {
+            on_chat_load_error(&app, &name, &e);
+            e.to_string()
+        })
+}
 
 #[tauri::command]
 fn get_first_run(state: tauri::State<'_, AppState>)-> Result<bool, String> {
     // load_storage_key();
-     if load_storage_key(&state.entry).is_none() {
+     if load_stored_key(&state.entry).is_none() {
         Ok(true)
     } else {
         Ok(false)
@@ -348,12 +1894,48 @@ pub fn run() {
             send_greet, 
             find_peer, 
             send_message, 
+            send_file,
+            save_attachment,
             setup_password, 
             unlock_app, 
+            lock_app,
+            report_activity,
+            is_locked,
+            change_password,
+            export_backup,
+            import_backup,
+            request_device_link,
+            approve_device,
+            unlink_device,
+            confirm_device_move,
+            list_devices,
+            get_peer_reputation,
+            list_contacts,
+            save_contact,
+            remove_contact,
+            set_contact_blocked,
+            set_contact_verified,
+            get_safety_number,
+            verify_safety_number,
             get_self_peer_id, 
             get_history_message, 
-            get_first_run
+            get_history_page,
+            search_messages,
+            get_first_run,
+            create_group,
+            invite_to_group,
+            join_group,
+            leave_group,
+            list_groups,
+            send_group_message,
+            get_group_history,
+            get_group_history_page
             ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                tauri::async_runtime::block_on(persist_peers(app));
+            }
+        });
 }
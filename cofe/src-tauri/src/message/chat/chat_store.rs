@@ -1,43 +1,570 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use crate::{APP_DATA_DIR, ChatMessage, security::security::{decrypt, encrypt}};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
 
-pub fn save_chat(
-    peer_id: String,
-    message: &Vec<ChatMessage>,
+use crate::{APP_DATA_DIR, security::security::{decrypt, encrypt, key_in_use, SecurityError}};
+
+// A history `name` is stored as two files:
+//   name.log  records of [u32 len][16 byte id tag][encrypt(json)], append only
+//   name.idx  one [u64 offset][16 byte id tag] entry per record, for seeking
+// The id tag is an HMAC of the message id under the storage key, so
+// duplicates can be spotted without decrypting anything.
+
+const TAG_LEN: usize = 16;
+const RECORD_HEADER: u64 = 4 + TAG_LEN as u64;
+const INDEX_ENTRY: u64 = 8 + TAG_LEN as u64;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Crypto(SecurityError),
+    Format(serde_json::Error),
+    NotFound,
+}
+
+impl StoreError {
+    /// The data on disk is unreadable, as opposed to the disk being unavailable
+    /// or the key having been replaced.
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            StoreError::Crypto(e) => *e != SecurityError::StaleKey,
+            StoreError::Format(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "chat store io error: {e}"),
+            StoreError::Crypto(e) => write!(f, "chat store is unreadable: {e}"),
+            StoreError::Format(e) => write!(f, "chat store is malformed: {e}"),
+            StoreError::NotFound => write!(f, "message not found"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<SecurityError> for StoreError {
+    fn from(e: SecurityError) -> Self {
+        StoreError::Crypto(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Format(e)
+    }
+}
+
+/// Held while a log and its index are being written or repaired.
+static CHAT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Index entries covered and id tags, per index path and storage key since
+/// the tags change with the key.
+type TagCache = HashMap<(PathBuf, [u8; TAG_LEN]), (u64, HashSet<[u8; TAG_LEN]>)>;
+
+/// Tags of every history appended to, so the duplicate check does not read
+/// the whole index.
+static ID_TAGS: Lazy<Mutex<TagCache>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Appends one message to the history `name`, `peer_id` for direct chats and
+/// `group_history_name` for groups. Returns `false` when a message with the
+/// same `id` is already stored.
+pub fn append_chat<T: Serialize>(
+    name: &str,
+    id: &str,
+    message: &T,
     key: &[u8; 32],
-    base_dir: PathBuf
-) -> Result<(), Box<dyn std::error::Error>>{
-    let json = serde_json::to_vec(message)?;
-    let encrypted = encrypt(&json, key);
+    base_dir: PathBuf,
+) -> Result<bool, StoreError> {
+    let _key = key_in_use(key)?;
+    let _guard = CHAT_LOCK.lock().unwrap();
+    let (log_path, idx_path) = open_log(name, key, &base_dir)?;
+
+    let tag = id_tag(id, key);
+    let mut cache = ID_TAGS.lock().unwrap();
+    let (entries, tags) = cached_tags(&mut cache, &idx_path, key)?;
+    if tags.contains(&tag) {
+        return Ok(false);
+    }
 
-    let mut path = base_dir;
-    path.push(format!("{}.enc", peer_id));
+    let mut log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+    let offset = log.seek(SeekFrom::End(0))?;
+    write_record(&mut log, &tag, &serde_json::to_vec(message)?, key)?;
+    log.sync_data()?;
 
-    fs::write(path, encrypted)?;
-    Ok(())
+    // The index goes second, a crash in between is repaired by `open_log`
+    append_index(&idx_path, offset, &tag)?;
+    *entries += 1;
+    tags.insert(tag);
+    Ok(true)
+}
+
+/// Whether a message with `id` is stored in the history `name`.
+pub fn has_message(
+    name: &str,
+    id: &str,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<bool, StoreError> {
+    let _guard = CHAT_LOCK.lock().unwrap();
+    let (_, idx_path) = open_log(name, key, &base_dir)?;
+
+    let mut cache = ID_TAGS.lock().unwrap();
+    let (_, tags) = cached_tags(&mut cache, &idx_path, key)?;
+    Ok(tags.contains(&id_tag(id, key)))
+}
+
+pub fn load_chat<T: DeserializeOwned>(
+    name: &str,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<Vec<T>, StoreError> {
+    let len = chat_len(name, key, base_dir.clone())?;
+    load_chat_range(name, 0, len, key, base_dir)
 }
 
-pub fn load_chat(
-    peer_id: &str,
+/// Number of messages in the history `name`.
+pub fn chat_len(
+    name: &str,
     key: &[u8; 32],
     base_dir: PathBuf,
-) -> Result<Vec<ChatMessage>, Box<dyn std::error::Error>> {
+) -> Result<usize, StoreError> {
+    let _guard = CHAT_LOCK.lock().unwrap();
+    let (_, idx_path) = open_log(name, key, &base_dir)?;
+    Ok(index_len(&idx_path)? as usize)
+}
 
-    let mut path = base_dir;
-    path.push(format!("{}.enc", peer_id));
+/// Messages `start..end` of the history `name`, oldest first. Only those
+/// records are read and decrypted.
+pub fn load_chat_range<T: DeserializeOwned>(
+    name: &str,
+    start: usize,
+    end: usize,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<Vec<T>, StoreError> {
+    let _guard = CHAT_LOCK.lock().unwrap();
+    let (log_path, idx_path) = open_log(name, key, &base_dir)?;
 
-    if !path.exists() {
+    let end = end.min(index_len(&idx_path)? as usize);
+    if start >= end {
         return Ok(vec![]);
     }
 
-    let encrypted = std::fs::read(path)?;
-    let decrypted = decrypt(&encrypted, key);
+    let mut log = File::open(&log_path)?;
+    log.seek(SeekFrom::Start(read_index_offset(&idx_path, start as u64)?))?;
 
-    let messages = serde_json::from_slice(&decrypted)?;
+    let mut messages = Vec::with_capacity(end - start);
+    for _ in start..end {
+        let data = read_record(&mut log)?;
+        messages.push(serde_json::from_slice(&decrypt(&data, key)?)?);
+    }
     Ok(messages)
 }
 
+/// Where a history page starts, pages never include the anchor message itself.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum HistoryCursor {
+    BeforeId { id: String },
+    AfterId { id: String },
+    Before { timestamp: i64 },
+    After { timestamp: i64 },
+}
+
+/// Up to `limit` messages next to `cursor`, oldest first, or the newest
+/// `limit` messages without one. Timestamp cursors binary search the log, so
+/// they assume it is in time order, which holds up to clock skew between peers.
+pub fn load_chat_page<T: DeserializeOwned>(
+    name: &str,
+    cursor: Option<&HistoryCursor>,
+    limit: usize,
+    timestamp: impl Fn(&T) -> i64,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<Vec<T>, StoreError> {
+    let len = chat_len(name, key, base_dir.clone())?;
+
+    let position = |id: &str| -> Result<usize, StoreError> {
+        find_chat(name, id, key, base_dir.clone())?.ok_or(StoreError::NotFound)
+    };
+    // First message for which `after(timestamp)` holds
+    let partition = |after: &dyn Fn(i64) -> bool| -> Result<usize, StoreError> {
+        let (mut lo, mut hi) = (0, len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let message: Vec<T> = load_chat_range(name, mid, mid + 1, key, base_dir.clone())?;
+            if message.first().is_some_and(|m| after(timestamp(m))) {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Ok(lo)
+    };
+
+    let (start, end) = match cursor {
+        None => (len.saturating_sub(limit), len),
+        Some(HistoryCursor::BeforeId { id }) => {
+            let end = position(id)?;
+            (end.saturating_sub(limit), end)
+        }
+        Some(HistoryCursor::AfterId { id }) => {
+            let start = position(id)? + 1;
+            (start, start + limit)
+        }
+        Some(HistoryCursor::Before { timestamp }) => {
+            let end = partition(&|t| t >= *timestamp)?;
+            (end.saturating_sub(limit), end)
+        }
+        Some(HistoryCursor::After { timestamp }) => {
+            let start = partition(&|t| t > *timestamp)?;
+            (start, start + limit)
+        }
+    };
+
+    load_chat_range(name, start, end, key, base_dir)
+}
+
+/// Decrypts the first and last message of the history `name`, enough to tell
+/// a file sealed under another key or mangled on disk.
+pub fn verify_chat(
+    name: &str,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<(), StoreError> {
+    let len = chat_len(name, key, base_dir.clone())?;
+    if len > 0 {
+        load_chat_range::<serde_json::Value>(name, 0, 1, key, base_dir.clone())?;
+        load_chat_range::<serde_json::Value>(name, len - 1, len, key, base_dir)?;
+    }
+    Ok(())
+}
+
+/// Position of the message `id` in the history `name`.
+pub fn find_chat(
+    name: &str,
+    id: &str,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<Option<usize>, StoreError> {
+    let _guard = CHAT_LOCK.lock().unwrap();
+    let (_, idx_path) = open_log(name, key, &base_dir)?;
+
+    let tag = id_tag(id, key);
+    Ok(read_index_tags(&idx_path)?.iter().position(|t| *t == tag))
+}
+
+/// Names of all stored histories, direct chats and groups alike.
+pub fn chat_names(base_dir: PathBuf) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(base_dir) else {
+        return vec![];
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("log" | "enc")))
+        .filter_map(|p| Some(p.file_stem()?.to_str()?.to_string()))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Brings the log for `name` into a consistent state and returns its paths.
+/// Histories in the old single-file format are converted on first use.
+fn open_log(
+    name: &str,
+    key: &[u8; 32],
+    base_dir: &Path,
+) -> Result<(PathBuf, PathBuf), StoreError> {
+    let log_path = base_dir.join(format!("{}.log", name));
+    let idx_path = base_dir.join(format!("{}.idx", name));
+    let legacy_path = base_dir.join(format!("{}.enc", name));
+
+    if legacy_path.exists() && !log_path.exists() {
+        migrate_legacy(&legacy_path, &log_path, &idx_path, key)?;
+    }
+    if !log_path.exists() {
+        return Ok((log_path, idx_path));
+    }
+
+    repair_log(&log_path, &idx_path)?;
+    Ok((log_path, idx_path))
+}
+
+/// Drops a torn record at the end of the log and indexes records that made it
+/// to the log but not to the index.
+fn repair_log(log_path: &Path, idx_path: &Path) -> Result<(), StoreError> {
+    let mut log = OpenOptions::new().read(true).write(true).open(log_path)?;
+    let log_len = log.metadata()?.len();
+
+    let mut entries = index_len(idx_path)?;
+    let idx = OpenOptions::new().create(true).truncate(false).write(true).open(idx_path)?;
+    idx.set_len(entries * INDEX_ENTRY)?;
+
+    // Where the last indexed record ends, stepping back over entries that
+    // point past the end of the log
+    let mut pos = 0;
+    while entries > 0 {
+        let offset = read_index_offset(idx_path, entries - 1)?;
+        if let Some(end) = record_end(&mut log, offset, log_len)? {
+            pos = end;
+            break;
+        }
+        entries -= 1;
+        idx.set_len(entries * INDEX_ENTRY)?;
+    }
+
+    while let Some(end) = record_end(&mut log, pos, log_len)? {
+        let mut tag = [0u8; TAG_LEN];
+        log.seek(SeekFrom::Start(pos + 4))?;
+        log.read_exact(&mut tag)?;
+        append_index(idx_path, pos, &tag)?;
+        pos = end;
+    }
+
+    if pos < log_len {
+        log::warn!("dropping {} torn bytes from {}", log_len - pos, log_path.display());
+        log.set_len(pos)?;
+    }
+    Ok(())
+}
+
+fn migrate_legacy(
+    legacy_path: &Path,
+    log_path: &Path,
+    idx_path: &Path,
+    key: &[u8; 32],
+) -> Result<(), StoreError> {
+    let decrypted = decrypt(&fs::read(legacy_path)?, key)?;
+    let messages: Vec<serde_json::Value> = serde_json::from_slice(&decrypted)?;
+
+    write_log(&messages, log_path, idx_path, key)?;
+    fs::remove_file(legacy_path)?;
+    Ok(())
+}
+
+/// Writes the history `name` from `src_dir` into `dst_dir` under `new_key`.
+pub fn rekey_chat(
+    name: &str,
+    old_key: &[u8; 32],
+    new_key: &[u8; 32],
+    src_dir: PathBuf,
+    dst_dir: &Path,
+) -> Result<(), StoreError> {
+    let messages: Vec<serde_json::Value> = load_chat(name, old_key, src_dir)?;
+    write_log(
+        &messages,
+        &dst_dir.join(format!("{}.log", name)),
+        &dst_dir.join(format!("{}.idx", name)),
+        new_key,
+    )
+}
+
+/// Writes `messages` as the whole history `name`, replacing any existing one.
+pub fn restore_chat(
+    name: &str,
+    messages: &[serde_json::Value],
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<(), StoreError> {
+    // Names come from outside when restoring a backup, keep them inside `base_dir`
+    if name.is_empty() || name.contains(['/', '\\', '.']) {
+        return Err(StoreError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid chat name {name:?}"),
+        )));
+    }
+
+    let _key = key_in_use(key)?;
+    let _guard = CHAT_LOCK.lock().unwrap();
+    write_log(
+        messages,
+        &base_dir.join(format!("{}.log", name)),
+        &base_dir.join(format!("{}.idx", name)),
+        key,
+    )
+}
+
+/// Writes a complete log and index in one go, the log only appears once whole.
+fn write_log(
+    messages: &[serde_json::Value],
+    log_path: &Path,
+    idx_path: &Path,
+    key: &[u8; 32],
+) -> Result<(), StoreError> {
+    let tmp_log = log_path.with_extension("log.tmp");
+    let mut log = File::create(&tmp_log)?;
+    let mut index = Vec::new();
+    let mut offset = 0u64;
+
+    for message in messages {
+        let id = message.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+        let tag = id_tag(id, key);
+        let written = write_record(&mut log, &tag, &serde_json::to_vec(message)?, key)?;
+
+        index.extend_from_slice(&offset.to_be_bytes());
+        index.extend_from_slice(&tag);
+        offset += written;
+    }
+    log.sync_all()?;
+
+    fs::write(idx_path, index)?;
+    fs::rename(tmp_log, log_path)?;
+    forget_tags(idx_path);
+    Ok(())
+}
+
+fn write_record(log: &mut File, tag: &[u8; TAG_LEN], data: &[u8], key: &[u8; 32]) -> Result<u64, StoreError> {
+    let encrypted = encrypt(data, key);
+    let mut record = Vec::with_capacity(RECORD_HEADER as usize + encrypted.len());
+    record.extend_from_slice(&(encrypted.len() as u32).to_be_bytes());
+    record.extend_from_slice(tag);
+    record.extend_from_slice(&encrypted);
+
+    log.write_all(&record)?;
+    Ok(record.len() as u64)
+}
+
+fn read_record(log: &mut File) -> Result<Vec<u8>, StoreError> {
+    let mut len = [0u8; 4];
+    log.read_exact(&mut len)?;
+    log.seek(SeekFrom::Current(TAG_LEN as i64))?;
+
+    let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+    log.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// End of the record starting at `offset`, or `None` if it is not all there.
+fn record_end(log: &mut File, offset: u64, log_len: u64) -> Result<Option<u64>, StoreError> {
+    if offset + RECORD_HEADER > log_len {
+        return Ok(None);
+    }
+
+    let mut len = [0u8; 4];
+    log.seek(SeekFrom::Start(offset))?;
+    log.read_exact(&mut len)?;
+
+    let end = offset + RECORD_HEADER + u32::from_be_bytes(len) as u64;
+    Ok((end <= log_len).then_some(end))
+}
+
+fn append_index(idx_path: &Path, offset: u64, tag: &[u8; TAG_LEN]) -> Result<(), StoreError> {
+    let mut idx = OpenOptions::new().create(true).append(true).open(idx_path)?;
+    idx.write_all(&[&offset.to_be_bytes()[..], tag].concat())?;
+    Ok(())
+}
+
+/// The cached tags of `idx_path`, read again when the index no longer has
+/// as many entries as the cache has seen, a repair or rewrite changed it.
+fn cached_tags<'a>(
+    cache: &'a mut TagCache,
+    idx_path: &Path,
+    key: &[u8; 32],
+) -> Result<(&'a mut u64, &'a mut HashSet<[u8; TAG_LEN]>), StoreError> {
+    let len = index_len(idx_path)?;
+    let (entries, tags) = cache.entry((idx_path.to_path_buf(), id_tag("", key))).or_default();
+
+    if *entries != len {
+        *tags = read_index_tags(idx_path)?.into_iter().collect();
+        *entries = len;
+    }
+    Ok((entries, tags))
+}
+
+/// Drops the cached tags of `idx_path` after it was replaced or moved.
+fn forget_tags(idx_path: &Path) {
+    ID_TAGS.lock().unwrap().retain(|(path, _), _| path != idx_path);
+}
+
+fn read_index_tags(idx_path: &Path) -> Result<Vec<[u8; TAG_LEN]>, StoreError> {
+    if !idx_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let bytes = fs::read(idx_path)?;
+    Ok(bytes
+        .chunks_exact(INDEX_ENTRY as usize)
+        .map(|entry| entry[8..].try_into().unwrap())
+        .collect())
+}
+
+fn read_index_offset(idx_path: &Path, n: u64) -> Result<u64, StoreError> {
+    let mut idx = File::open(idx_path)?;
+    idx.seek(SeekFrom::Start(n * INDEX_ENTRY))?;
+
+    let mut offset = [0u8; 8];
+    idx.read_exact(&mut offset)?;
+    Ok(u64::from_be_bytes(offset))
+}
+
+fn index_len(idx_path: &Path) -> Result<u64, StoreError> {
+    if !idx_path.exists() {
+        return Ok(0);
+    }
+    Ok(fs::metadata(idx_path)?.len() / INDEX_ENTRY)
+}
+
+fn id_tag(id: &str, key: &[u8; 32]) -> [u8; TAG_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("hmac accepts any key length");
+    mac.update(b"cofe/chat-id");
+    mac.update(id.as_bytes());
+
+    let mut tag = [0u8; TAG_LEN];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+    tag
+}
+
+/// Moves every file of the history `name` into the recovery folder, so one
+/// unreadable conversation does not keep failing every load.
+pub fn quarantine_chat(name: &str, base_dir: PathBuf) -> Result<PathBuf, StoreError> {
+    let _guard = CHAT_LOCK.lock().unwrap();
+    let dest = recovery_dir().join(format!("{}-{}", name, chrono::Utc::now().timestamp_millis()));
+    fs::create_dir_all(&dest)?;
+
+    for ext in ["log", "idx", "enc"] {
+        let path = base_dir.join(format!("{}.{}", name, ext));
+        if path.exists() {
+            fs::rename(&path, dest.join(format!("{}.{}", name, ext)))?;
+        }
+    }
+    forget_tags(&base_dir.join(format!("{}.idx", name)));
+
+    log::warn!("moved unreadable chat {name} to {}", dest.display());
+    Ok(dest)
+}
+
+pub fn recovery_dir() -> PathBuf {
+    let mut dir = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    dir.push("recovery");
+    std::fs::create_dir_all(&dir).ok();
+    dir
+}
+
 pub fn chat_dir() -> PathBuf {
     let mut dir = APP_DATA_DIR.get().expect("app dir not initialized").clone();
     dir.push("chats");
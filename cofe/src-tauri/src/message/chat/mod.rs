@@ -1 +1,2 @@
-pub mod chat_store;
\ No newline at end of file
+pub mod chat_store;
+pub mod outbox;
\ No newline at end of file
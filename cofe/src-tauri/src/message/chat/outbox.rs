@@ -0,0 +1,108 @@
+use std::{fs, path::PathBuf, sync::Mutex};
+
+use crate::{APP_DATA_DIR, ChatMessage, security::security::{decrypt, encrypt, key_in_use}};
+
+/// Held across read-modify-write cycles on any outbox.
+static OUTBOX_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn save_outbox(
+    peer_id: &str,
+    messages: &[ChatMessage],
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let mut path = base_dir;
+    path.push(format!("{}.enc", peer_id));
+
+    if messages.is_empty() {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+
+    let json = serde_json::to_vec(messages)?;
+    let encrypted = encrypt(&json, key);
+
+    // A crash mid write must not take the whole outbox with it
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encrypted)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn load_outbox(
+    peer_id: &str,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<Vec<ChatMessage>, Box<dyn std::error::Error>> {
+    let mut path = base_dir;
+    path.push(format!("{}.enc", peer_id));
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    let messages = serde_json::from_slice(&decrypted)?;
+    Ok(messages)
+}
+
+/// Loads the outbox of `peer_id`, applies `update` and writes it back under `OUTBOX_LOCK`.
+pub fn update_outbox<T>(
+    peer_id: &str,
+    key: &[u8; 32],
+    update: impl FnOnce(&mut Vec<ChatMessage>) -> T,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let _guard = OUTBOX_LOCK.lock().unwrap();
+    let mut messages = load_outbox(peer_id, key, outbox_dir())?;
+    let result = update(&mut messages);
+    save_outbox(peer_id, &messages, key, outbox_dir())?;
+    Ok(result)
+}
+
+pub fn enqueue_outbox(
+    peer_id: &str,
+    message: ChatMessage,
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    update_outbox(peer_id, key, |messages| {
+        if !messages.iter().any(|m| m.id == message.id) {
+            messages.push(message);
+        }
+    })
+}
+
+pub fn remove_from_outbox(
+    peer_id: &str,
+    message_id: &str,
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    update_outbox(peer_id, key, |messages| messages.retain(|m| m.id != message_id))
+}
+
+/// Peers that still have undelivered messages waiting in the outbox.
+pub fn outbox_peers(base_dir: PathBuf) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(base_dir) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            name.strip_suffix(".enc").map(|s| s.to_string())
+        })
+        .collect()
+}
+
+pub fn outbox_dir() -> PathBuf {
+    let mut dir = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    dir.push("outbox");
+    std::fs::create_dir_all(&dir).ok();
+    dir
+}
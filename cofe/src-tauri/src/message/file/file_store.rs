@@ -0,0 +1,431 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{APP_DATA_DIR, security::security::{decrypt, encrypt, key_in_use}};
+
+/// Plaintext bytes per chunk, both on disk and on the wire.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+// nonce + AES-GCM tag added by `encrypt`
+const RECORD_OVERHEAD: u64 = 12 + 16;
+
+/// File reference carried inside an end-to-end encrypted `ChatMessage`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub file_id: String,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Encrypts chunks on the wire, only the two chat parties know it.
+    pub key: [u8; 32],
+}
+
+/// Held across read-modify-write cycles on the file index.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileRecord {
+    pub attachment: Attachment,
+    pub peer: PeerId,
+    pub outgoing: bool,
+    pub complete: bool,
+    /// Stored under the sender's peer id, false for downloads from before that.
+    #[serde(default)]
+    pub scoped: bool,
+}
+
+impl FileRecord {
+    /// Name of the file on disk. Downloads are prefixed with the sender, whose
+    /// chosen `file_id` must not collide with our own or another peer's files.
+    pub fn local_id(&self) -> String {
+        if self.outgoing || !self.scoped {
+            self.attachment.file_id.clone()
+        } else {
+            format!("{}-{}", self.peer, self.attachment.file_id)
+        }
+    }
+}
+
+/// Ids are 16 random bytes in lowercase hex, anything else did not come from `import_file`.
+pub fn is_valid_file_id(file_id: &str) -> bool {
+    file_id.len() == 32 && file_id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn load_file_index(
+    key: &[u8; 32],
+) -> Result<Vec<FileRecord>, Box<dyn std::error::Error>> {
+    let path = files_dir().join("index.enc");
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    Ok(serde_json::from_slice(&decrypted)?)
+}
+
+pub fn save_file_index(
+    records: &[FileRecord],
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let json = serde_json::to_vec(records)?;
+    fs::write(files_dir().join("index.enc"), encrypt(&json, key))?;
+    Ok(())
+}
+
+/// Loads the index, applies `update` and writes it back under `INDEX_LOCK`.
+pub fn update_file_index<T>(
+    key: &[u8; 32],
+    update: impl FnOnce(&mut Vec<FileRecord>) -> T,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut records = load_file_index(key)?;
+    let result = update(&mut records);
+    save_file_index(&records, key)?;
+    Ok(result)
+}
+
+/// Copies `src` into the encrypted file store so it can be served later.
+pub fn import_file(
+    src: &Path,
+    max_size: u64,
+    key: &[u8; 32],
+) -> Result<Attachment, Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let size = fs::metadata(src)?.len();
+    if size > max_size {
+        return Err(format!("file is {size} bytes, the limit is {max_size}").into());
+    }
+
+    let file_id = hex::encode(rand::random::<[u8; 16]>());
+    let mut input = File::open(src)?;
+    let mut output = File::create(stored_path(&file_id))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+
+    loop {
+        let n = read_full(&mut input, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        write_record(&mut output, &buf[..n], key)?;
+    }
+
+    let name = src
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file_id.clone());
+
+    Ok(Attachment {
+        file_id,
+        name,
+        size,
+        sha256: hex::encode(hasher.finalize()),
+        key: rand::random::<[u8; 32]>(),
+    })
+}
+
+pub fn read_chunk(
+    local_id: &str,
+    offset: u64,
+    key: &[u8; 32],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if !offset.is_multiple_of(CHUNK_SIZE) {
+        return Err("offset is not on a chunk boundary".into());
+    }
+
+    let mut file = File::open(stored_path(local_id))?;
+    file.seek(SeekFrom::Start(offset / CHUNK_SIZE * (CHUNK_SIZE + RECORD_OVERHEAD + 4)))?;
+    read_record(&mut file, key)
+}
+
+/// Bytes of `local_id` already downloaded, which is where a resumed transfer
+/// continues. A record cut short by a crash is dropped here.
+pub fn downloaded_bytes(local_id: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let path = partial_path(local_id);
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let len = file.metadata()?.len();
+    let (whole, bytes) = walk_records(&mut file, len)?;
+
+    if whole != len {
+        file.set_len(whole)?;
+    }
+    Ok(bytes)
+}
+
+/// Follows the length prefixes from the start, the last record may be
+/// shorter than a full chunk. Returns where the last whole record ends and
+/// the plaintext bytes up to there, a record running past `len` is torn.
+fn walk_records(file: &mut (impl Read + Seek), len: u64) -> std::io::Result<(u64, u64)> {
+    let mut pos = 0u64;
+    let mut bytes = 0u64;
+    file.seek(SeekFrom::Start(0))?;
+
+    while pos + 4 <= len {
+        let mut prefix = [0u8; 4];
+        file.read_exact(&mut prefix)?;
+        let record = u32::from_be_bytes(prefix) as u64;
+
+        if pos + 4 + record > len {
+            break;
+        }
+        if record < RECORD_OVERHEAD {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "record shorter than its overhead"));
+        }
+
+        bytes += record - RECORD_OVERHEAD;
+        pos += 4 + record;
+        file.seek(SeekFrom::Start(pos))?;
+    }
+
+    Ok((pos, bytes))
+}
+
+pub fn append_chunk(
+    local_id: &str,
+    data: &[u8],
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(partial_path(local_id))?;
+    write_record(&mut file, data, key)
+}
+
+/// Checks the downloaded file against the sender's hash and moves it into place.
+pub fn finalize_download(
+    local_id: &str,
+    attachment: &Attachment,
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = partial_path(local_id);
+    // An empty file never gets a chunk, so there may be nothing on disk yet
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+    let mut hasher = Sha256::new();
+    let mut total = 0u64;
+
+    while total < attachment.size {
+        let chunk = read_record(&mut file, key)?;
+        total += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+
+    if total != attachment.size || hex::encode(hasher.finalize()) != attachment.sha256 {
+        fs::remove_file(&path)?;
+        return Err("file failed its integrity check".into());
+    }
+
+    fs::rename(path, stored_path(local_id))?;
+    Ok(())
+}
+
+pub fn export_file(
+    local_id: &str,
+    dest: &Path,
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = File::open(stored_path(local_id))?;
+    let mut output = File::create(dest)?;
+    let len = input.metadata()?.len();
+
+    while input.stream_position()? < len {
+        output.write_all(&read_record(&mut input, key)?)?;
+    }
+    Ok(())
+}
+
+/// Hands each plaintext chunk of the stored file `local_id` to `each`, in order.
+pub fn read_stored_file(
+    local_id: &str,
+    key: &[u8; 32],
+    mut each: impl FnMut(&[u8]) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = File::open(stored_path(local_id))?;
+    let len = input.metadata()?.len();
+
+    while input.stream_position()? < len {
+        each(&read_record(&mut input, key)?)?;
+    }
+    Ok(())
+}
+
+/// Writes the stored file `local_id` from the chunks `next` returns until it
+/// returns `None`. The file only appears once whole.
+pub fn write_stored_file(
+    local_id: &str,
+    key: &[u8; 32],
+    mut next: impl FnMut() -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let tmp = files_dir().join(format!("{}.tmp", local_id));
+    let mut output = File::create(&tmp)?;
+
+    while let Some(chunk) = next()? {
+        write_record(&mut output, &chunk, key)?;
+    }
+    output.sync_all()?;
+
+    fs::rename(tmp, stored_path(local_id))?;
+    Ok(())
+}
+
+/// Re-encrypts every stored and partial file plus the index into `dst_dir`.
+pub fn rekey_files(
+    old_key: &[u8; 32],
+    new_key: &[u8; 32],
+    dst_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dst_dir)?;
+
+    for entry in fs::read_dir(files_dir())? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+        let dst = dst_dir.join(file_name);
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("enc") if file_name == "index.enc" => {
+                fs::write(dst, encrypt(&decrypt(&fs::read(&path)?, old_key)?, new_key))?;
+            }
+            Some("enc" | "part") => {
+                if path.extension().is_some_and(|e| e == "part") {
+                    // Drop a torn record so the copy only has whole ones
+                    downloaded_bytes(path.file_stem().and_then(|s| s.to_str()).unwrap_or_default())?;
+                }
+
+                let mut input = File::open(&path)?;
+                let mut output = File::create(dst)?;
+                let len = input.metadata()?.len();
+                while input.stream_position()? < len {
+                    write_record(&mut output, &read_record(&mut input, old_key)?, new_key)?;
+                }
+                output.sync_all()?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn write_record(file: &mut File, data: &[u8], key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+    let encrypted = encrypt(data, key);
+    file.write_all(&(encrypted.len() as u32).to_be_bytes())?;
+    file.write_all(&encrypted)?;
+    Ok(())
+}
+
+fn read_record(file: &mut File, key: &[u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len)?;
+
+    let mut encrypted = vec![0u8; u32::from_be_bytes(len) as usize];
+    file.read_exact(&mut encrypted)?;
+    Ok(decrypt(&encrypted, key)?)
+}
+
+fn read_full(input: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn stored_path(local_id: &str) -> PathBuf {
+    files_dir().join(format!("{}.enc", local_id))
+}
+
+fn partial_path(local_id: &str) -> PathBuf {
+    files_dir().join(format!("{}.part", local_id))
+}
+
+pub fn files_dir() -> PathBuf {
+    let mut dir = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    dir.push("files");
+    std::fs::create_dir_all(&dir).ok();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn record(plaintext: u64) -> Vec<u8> {
+        let len = plaintext + RECORD_OVERHEAD;
+        let mut data = (len as u32).to_be_bytes().to_vec();
+        data.resize(4 + len as usize, 0xab);
+        data
+    }
+
+    #[test]
+    fn walk_counts_a_short_last_record() {
+        let mut data = record(CHUNK_SIZE);
+        data.extend(record(CHUNK_SIZE));
+        data.extend(record(100));
+        let len = data.len() as u64;
+
+        assert_eq!(walk_records(&mut Cursor::new(data), len).unwrap(), (len, 2 * CHUNK_SIZE + 100));
+    }
+
+    #[test]
+    fn walk_drops_a_torn_record() {
+        let mut data = record(CHUNK_SIZE);
+        let whole = data.len() as u64;
+        let torn = record(CHUNK_SIZE);
+        data.extend(&torn[..torn.len() / 2]);
+        let len = data.len() as u64;
+
+        assert_eq!(walk_records(&mut Cursor::new(data), len).unwrap(), (whole, CHUNK_SIZE));
+    }
+
+    #[test]
+    fn walk_drops_a_torn_length_prefix() {
+        let mut data = record(10);
+        let whole = data.len() as u64;
+        data.extend([0, 0]);
+        let len = data.len() as u64;
+
+        assert_eq!(walk_records(&mut Cursor::new(data), len).unwrap(), (whole, 10));
+    }
+
+    #[test]
+    fn walk_of_an_empty_file() {
+        assert_eq!(walk_records(&mut Cursor::new(Vec::new()), 0).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn file_ids_must_be_hex() {
+        assert!(is_valid_file_id(&hex::encode(rand::random::<[u8; 16]>())));
+
+        assert!(!is_valid_file_id(""));
+        assert!(!is_valid_file_id("../../../../etc/passwd"));
+        assert!(!is_valid_file_id("../aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+        assert!(!is_valid_file_id("0123456789ABCDEF0123456789ABCDEF"));
+        assert!(!is_valid_file_id("0123456789abcdef0123456789abcde"));
+        assert!(!is_valid_file_id("0123456789abcdef0123456789abcdef0"));
+    }
+}
@@ -0,0 +1 @@
+pub mod file_store;
\ No newline at end of file
@@ -0,0 +1,82 @@
+use std::{fs, path::PathBuf};
+
+use libp2p::{gossipsub::IdentTopic, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::{APP_DATA_DIR, security::security::{decrypt, encrypt, key_in_use}};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub admin: PeerId,
+    pub members: Vec<PeerId>,
+    pub key: [u8; 32],
+    pub joined: bool,
+}
+
+/// What the frontend gets to see of a group, everything but the key.
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupInfo {
+    pub id: String,
+    pub name: String,
+    pub admin: PeerId,
+    pub members: Vec<PeerId>,
+    pub joined: bool,
+}
+
+impl Group {
+    pub fn info(&self) -> GroupInfo {
+        GroupInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            admin: self.admin,
+            members: self.members.clone(),
+            joined: self.joined,
+        }
+    }
+
+    /// Name of the group's history file inside `chat_dir()`.
+    pub fn history_name(&self) -> String {
+        group_history_name(&self.id)
+    }
+}
+
+pub fn group_topic(group_id: &str) -> IdentTopic {
+    IdentTopic::new(format!("/agent/group/{}", group_id))
+}
+
+pub fn group_history_name(group_id: &str) -> String {
+    format!("group-{}", group_id)
+}
+
+pub fn save_groups(
+    groups: &[Group],
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let json = serde_json::to_vec(groups)?;
+    fs::write(groups_path(), encrypt(&json, key))?;
+    Ok(())
+}
+
+pub fn load_groups(
+    key: &[u8; 32],
+) -> Result<Vec<Group>, Box<dyn std::error::Error>> {
+    let path = groups_path();
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    Ok(serde_json::from_slice(&decrypted)?)
+}
+
+fn groups_path() -> PathBuf {
+    let mut path = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    path.push("groups.enc");
+    path
+}
@@ -0,0 +1 @@
+pub mod group_store;
\ No newline at end of file
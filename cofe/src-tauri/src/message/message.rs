@@ -1,16 +1,107 @@
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 
-use crate::ChatMessage;
+use crate::security::{ratchet::RatchetHeader, x3dh::{PreKeyBundle, X3dhInit}};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum GreetRequest {
     Syn { message: String },
-    Chat{ message: ChatMessage },
+    Chat{ envelope: ChatEnvelope },
+    PreKeyRequest,
+    GroupInvite { invite: GroupInvite },
+    /// Sent by a new device to the account it wants to join, `consent` is
+    /// its half of the certificate, see `security::device::consent_to_link`.
+    LinkRequest { consent: Vec<u8> },
+    /// Every certified device of the sender's account.
+    DeviceList { certs: Vec<DeviceCertificate> },
+}
+
+/// Wire form of a `ChatMessage`: routing fields stay readable, the
+/// content is sealed with the ratchet session for the recipient (see `security::e2e`).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatEnvelope {
+    pub id: String,
+    pub from: PeerId,
+    pub to: PeerId,
+    pub timestamp: i64,
+    pub header: RatchetHeader,
+    pub init: Option<X3dhInit>,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// Set when `from` is a linked device, ties it to its account.
+    #[serde(default)]
+    pub device: Option<Box<DeviceCertificate>>,
+}
+
+/// An account's primary key vouching for one of its other devices, and the
+/// device agreeing to belong to it. The account is known by the PeerId of its
+/// primary device.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DeviceCertificate {
+    pub account: PeerId,
+    pub device: PeerId,
+    pub issued_at: i64,
+    pub signature: Vec<u8>,
+    #[serde(default)]
+    pub device_signature: Vec<u8>,
+}
+
+/// Invitation into a group, the group key is wrapped for the invitee only.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupInvite {
+    pub group_id: String,
+    pub name: String,
+    pub admin: PeerId,
+    pub members: Vec<PeerId>,
+    pub nonce: [u8; 12],
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Gossipsub payload of a group message, encrypted under the group key.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupEnvelope {
+    pub group_id: String,
+    pub id: String,
+    pub from: PeerId,
+    pub timestamp: i64,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum GreetResponse {
     Ack { message: String },
+    Delivered { id: String },
+    Rejected { id: String, reason: String },
+    /// Arrived but could not be stored yet, the sender keeps it and tries again.
+    Deferred { id: String, reason: String },
+    /// `None` while the peer is locked and cannot answer handshakes.
+    PreKeys { bundle: Option<PreKeyBundle> },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+    /// The recipient refused the message, resending it will not help.
+    Rejected,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FileRequest {
+    Chunk { file_id: String, offset: u64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FileResponse {
+    /// `data` is the chunk at `offset`, encrypted with the attachment key.
+    Chunk { file_id: String, offset: u64, nonce: [u8; 12], data: Vec<u8> },
+    Denied { file_id: String, reason: String },
 }
@@ -1,2 +1,5 @@
 pub mod message;
-pub mod chat;
\ No newline at end of file
+pub mod chat;
+pub mod group;
+pub mod file;
+pub mod search;
\ No newline at end of file
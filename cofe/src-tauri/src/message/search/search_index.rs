@@ -0,0 +1,324 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    APP_DATA_DIR,
+    message::chat::chat_store::{chat_dir, chat_len, chat_names, load_chat_range},
+    security::security::{decrypt, encrypt, key_in_use},
+};
+
+// search/index.log holds one [16 byte token tag][u32 len][encrypt(posting)]
+// record per distinct word of every message. Tags are keyed HMACs, so the
+// file gives away neither the words nor which chat they came from.
+// search/state.enc remembers how far each history has been indexed.
+
+const TAG_LEN: usize = 16;
+const MAX_TOKEN_LEN: usize = 64;
+const SNIPPET_CONTEXT: usize = 40;
+
+type IndexRecord = ([u8; TAG_LEN], Vec<u8>);
+
+static SEARCH_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+struct Posting {
+    chat: String,
+    position: usize,
+}
+
+/// The fields search needs, shared by `ChatMessage` and `GroupMessage`.
+#[derive(Deserialize)]
+struct Searchable {
+    id: String,
+    from: String,
+    timestamp: i64,
+    content: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResult {
+    /// History the hit is in, a peer id or a `group_history_name`.
+    pub chat: String,
+    pub id: String,
+    pub from: String,
+    pub timestamp: i64,
+    pub snippet: String,
+}
+
+/// Messages containing every word of `query`, newest first.
+pub fn search_messages(
+    query: &str,
+    limit: usize,
+    key: &[u8; 32],
+) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let _guard = SEARCH_LOCK.lock().unwrap();
+    catch_up(key)?;
+
+    let words = tokenize(query);
+    if words.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let tags: Vec<[u8; TAG_LEN]> = words.iter().map(|w| token_tag(w, key)).collect();
+    let mut postings: Vec<HashSet<Posting>> = vec![HashSet::new(); tags.len()];
+
+    let path = index_path();
+    if path.exists() {
+        let mut index = BufReader::new(File::open(path)?);
+        while let Some((tag, data)) = read_record(&mut index)? {
+            if let Some(i) = tags.iter().position(|t| *t == tag) {
+                postings[i].insert(serde_json::from_slice(&decrypt(&data, key)?)?);
+            }
+        }
+    }
+
+    let mut hits = postings.pop().unwrap_or_default();
+    for other in &postings {
+        hits.retain(|p| other.contains(p));
+    }
+
+    let mut results = Vec::new();
+    for hit in hits {
+        let found: Vec<Searchable> = load_chat_range(&hit.chat, hit.position, hit.position + 1, key, chat_dir())?;
+        if let Some(message) = found.into_iter().next() {
+            results.push(SearchResult {
+                snippet: snippet(&message.content, &words),
+                chat: hit.chat,
+                id: message.id,
+                from: message.from,
+                timestamp: message.timestamp,
+            });
+        }
+    }
+
+    results.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
+    results.truncate(limit);
+    Ok(results)
+}
+
+/// Drops every posting of the history `chat`, which was replaced or set aside
+/// so its positions no longer point at the same messages. It is indexed
+/// again from the start on the next search.
+pub fn forget_chat(chat: &str, key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let _guard = SEARCH_LOCK.lock().unwrap();
+    let path = index_path();
+
+    if path.exists() {
+        let tmp = path.with_extension("log.tmp");
+        let mut kept = BufWriter::new(File::create(&tmp)?);
+        let mut index = BufReader::new(File::open(&path)?);
+
+        while let Some((tag, data)) = read_record(&mut index)? {
+            let posting: Posting = serde_json::from_slice(&decrypt(&data, key)?)?;
+            if posting.chat != chat {
+                kept.write_all(&tag)?;
+                kept.write_all(&(data.len() as u32).to_be_bytes())?;
+                kept.write_all(&data)?;
+            }
+        }
+
+        kept.flush()?;
+        kept.get_ref().sync_all()?;
+        fs::rename(tmp, &path)?;
+    }
+
+    // After the postings are gone, a crash in between only misses hits
+    let mut state = load_state(key)?;
+    if state.remove(chat).is_some() {
+        save_state(&state, key)?;
+    }
+    Ok(())
+}
+
+/// Indexes whatever reached the chat store since the last search.
+fn catch_up(key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = load_state(key)?;
+    drop_torn_tail(&index_path())?;
+    let mut index = BufWriter::new(OpenOptions::new().create(true).append(true).open(index_path())?);
+    let mut changed = false;
+
+    for chat in chat_names(chat_dir()) {
+        let indexed = state.get(&chat).copied().unwrap_or(0);
+        let len = chat_len(&chat, key, chat_dir())?;
+        if len <= indexed {
+            continue;
+        }
+
+        let messages: Vec<Searchable> = load_chat_range(&chat, indexed, len, key, chat_dir())?;
+        for (offset, message) in messages.iter().enumerate() {
+            let posting = serde_json::to_vec(&Posting { chat: chat.clone(), position: indexed + offset })?;
+            for word in tokenize(&message.content) {
+                let encrypted = encrypt(&posting, key);
+                index.write_all(&token_tag(&word, key))?;
+                index.write_all(&(encrypted.len() as u32).to_be_bytes())?;
+                index.write_all(&encrypted)?;
+            }
+        }
+
+        state.insert(chat, len);
+        changed = true;
+    }
+
+    // Postings must be on disk before the state says they are
+    index.flush()?;
+    index.get_ref().sync_data()?;
+    if changed {
+        save_state(&state, key)?;
+    }
+    Ok(())
+}
+
+/// Cuts a record left half written by a crash, so appends start on a boundary.
+fn drop_torn_tail(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let mut index = File::open(path)?;
+    let len = index.metadata()?.len();
+    let mut pos = 0;
+
+    while pos + TAG_LEN as u64 + 4 <= len {
+        let mut size = [0u8; 4];
+        index.seek(SeekFrom::Start(pos + TAG_LEN as u64))?;
+        index.read_exact(&mut size)?;
+
+        let end = pos + TAG_LEN as u64 + 4 + u32::from_be_bytes(size) as u64;
+        if end > len {
+            break;
+        }
+        pos = end;
+    }
+
+    if pos < len {
+        OpenOptions::new().write(true).open(path)?.set_len(pos)?;
+    }
+    Ok(())
+}
+
+fn read_record(index: &mut impl Read) -> Result<Option<IndexRecord>, Box<dyn std::error::Error>> {
+    let mut tag = [0u8; TAG_LEN];
+    match index.read_exact(&mut tag) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut len = [0u8; 4];
+    let mut data = Vec::new();
+    let complete = index.read_exact(&mut len).is_ok() && {
+        data.resize(u32::from_be_bytes(len) as usize, 0);
+        index.read_exact(&mut data).is_ok()
+    };
+
+    // Only the tail can be torn, and `drop_torn_tail` cuts it before the next append
+    Ok(complete.then_some((tag, data)))
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase().chars().take(MAX_TOKEN_LEN).collect())
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+fn token_tag(word: &str, key: &[u8; 32]) -> [u8; TAG_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("hmac accepts any key length");
+    mac.update(b"cofe/search-token");
+    mac.update(word.as_bytes());
+
+    let mut tag = [0u8; TAG_LEN];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+    tag
+}
+
+/// Text around whichever of `words` occurs first in `content`.
+fn snippet(content: &str, words: &[String]) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let lower: Vec<char> = content.to_lowercase().chars().collect();
+
+    // Lowercasing can change the length of some scripts, fall back to the start
+    let (at, len) = if lower.len() == chars.len() {
+        words
+            .iter()
+            .filter_map(|word| {
+                let word: Vec<char> = word.chars().collect();
+                let at = lower.windows(word.len()).position(|w| w == word.as_slice())?;
+                Some((at, word.len()))
+            })
+            .min()
+            .unwrap_or((0, 0))
+    } else {
+        (0, 0)
+    };
+
+    let start = at.saturating_sub(SNIPPET_CONTEXT);
+    let end = (at + len + SNIPPET_CONTEXT).min(chars.len());
+
+    let mut snippet: String = chars[start..end].iter().collect();
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+fn load_state(key: &[u8; 32]) -> Result<HashMap<String, usize>, Box<dyn std::error::Error>> {
+    let path = search_dir().join("state.enc");
+
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let encrypted = fs::read(path)?;
+    let state = decrypt(&encrypted, key)
+        .map_err(|e| e.to_string())
+        .and_then(|decrypted| serde_json::from_slice(&decrypted).map_err(|e| e.to_string()));
+
+    match state {
+        Ok(state) => Ok(state),
+        Err(e) => {
+            // Everything here is derived from the chat store, start over
+            log::warn!("search index is unreadable, rebuilding: {e}");
+            if index_path().exists() {
+                fs::remove_file(index_path())?;
+            }
+            Ok(HashMap::new())
+        }
+    }
+}
+
+fn save_state(state: &HashMap<String, usize>, key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_vec(state)?;
+    fs::write(search_dir().join("state.enc"), encrypt(&json, key))?;
+    Ok(())
+}
+
+fn index_path() -> PathBuf {
+    search_dir().join("index.log")
+}
+
+pub fn search_dir() -> PathBuf {
+    let mut dir = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    dir.push("search");
+    std::fs::create_dir_all(&dir).ok();
+    dir
+}
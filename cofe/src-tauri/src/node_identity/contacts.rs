@@ -0,0 +1,108 @@
+use std::{fs, path::PathBuf, sync::Mutex};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::{APP_DATA_DIR, security::security::{decrypt, encrypt, key_in_use}};
+
+/// Held across read-modify-write cycles on the contacts file.
+static CONTACT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Someone the user chose to name. `peer_id` is the contact's account, so
+/// the entry covers all of their linked devices.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Contact {
+    pub peer_id: PeerId,
+    pub name: String,
+    #[serde(default)]
+    pub notes: String,
+    /// SHA-256 of the avatar image, the image itself stays with the frontend.
+    #[serde(default)]
+    pub avatar_hash: Option<String>,
+    /// Set once the user compared safety numbers with the contact.
+    #[serde(default)]
+    pub verified: bool,
+    /// Hex fingerprint of the contact's keys when they were verified.
+    #[serde(default)]
+    pub verified_fingerprint: Option<String>,
+    /// Blocked peers cannot connect and anything they send is dropped.
+    #[serde(default)]
+    pub blocked: bool,
+    pub added_at: i64,
+}
+
+impl Contact {
+    pub fn new(peer_id: PeerId, name: String) -> Self {
+        Self {
+            peer_id,
+            name,
+            notes: String::new(),
+            avatar_hash: None,
+            verified: false,
+            verified_fingerprint: None,
+            blocked: false,
+            added_at: chrono::Utc::now().timestamp_millis(),
+        }
+    }
+}
+
+pub fn load_contacts(
+    key: &[u8; 32],
+) -> Result<Vec<Contact>, Box<dyn std::error::Error>> {
+    let path = contacts_path();
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    Ok(serde_json::from_slice(&decrypted)?)
+}
+
+pub fn save_contacts(
+    contacts: &[Contact],
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let json = serde_json::to_vec(contacts)?;
+    // A crash mid write must not take every contact with it
+    let path = contacts_path();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encrypt(&json, key))?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Loads the contacts, applies `update` and writes them back under `CONTACT_LOCK`.
+pub fn update_contacts<T>(
+    key: &[u8; 32],
+    update: impl FnOnce(&mut Vec<Contact>) -> T,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let _guard = CONTACT_LOCK.lock().unwrap();
+    let mut contacts = load_contacts(key)?;
+    let result = update(&mut contacts);
+    save_contacts(&contacts, key)?;
+    Ok(result)
+}
+
+/// Mutable access to the contact for `peer_id`, created with the PeerId as
+/// its name if there is none yet.
+pub fn contact_entry(contacts: &mut Vec<Contact>, peer_id: PeerId) -> &mut Contact {
+    let index = match contacts.iter().position(|c| c.peer_id == peer_id) {
+        Some(index) => index,
+        None => {
+            contacts.push(Contact::new(peer_id, peer_id.to_string()));
+            contacts.len() - 1
+        }
+    };
+    &mut contacts[index]
+}
+
+fn contacts_path() -> PathBuf {
+    let mut path = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    path.push("contacts.enc");
+    path
+}
@@ -0,0 +1,181 @@
+use std::{collections::{HashMap, HashSet}, fs, path::PathBuf, sync::Mutex};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::{APP_DATA_DIR, message::message::DeviceCertificate, security::security::{decrypt, encrypt, key_in_use}};
+
+/// Held across read-modify-write cycles on the device store.
+static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeviceStore {
+    /// Our certificate once this install is linked into another account.
+    pub own: Option<DeviceCertificate>,
+    /// Account we asked to join, no one else may certify us.
+    pub pending_link: Option<PeerId>,
+    /// Certified devices of every account we know of, ours included.
+    pub accounts: HashMap<PeerId, Vec<DeviceCertificate>>,
+    /// Devices their account dropped. Their certificates stay valid, so
+    /// anything they send about themselves is ignored.
+    #[serde(default)]
+    pub removed: HashSet<PeerId>,
+    /// Consents of devices that asked to join our account, by device.
+    #[serde(default)]
+    pub link_requests: HashMap<PeerId, Vec<u8>>,
+    /// Certificates for devices another account already has. They only
+    /// count once the user confirms the move with `confirm_move`.
+    #[serde(default)]
+    pub disputed: Vec<DeviceCertificate>,
+}
+
+impl DeviceStore {
+    /// The account we belong to, our own PeerId unless we are a linked device.
+    pub fn local_account(&self, local: &PeerId) -> PeerId {
+        self.own.as_ref().map(|c| c.account).unwrap_or(*local)
+    }
+
+    /// The account `peer` is a device of, `peer` itself if it is not linked.
+    pub fn account_of(&self, peer: &PeerId) -> PeerId {
+        self.accounts
+            .iter()
+            .find(|(_, certs)| certs.iter().any(|c| c.device == *peer))
+            .map(|(account, _)| *account)
+            .unwrap_or(*peer)
+    }
+
+    /// Every device messages for `account` go to, its primary first.
+    pub fn devices_of(&self, account: &PeerId) -> Vec<PeerId> {
+        let mut devices = vec![*account];
+        if let Some(certs) = self.accounts.get(account) {
+            devices.extend(certs.iter().map(|c| c.device));
+        }
+        devices
+    }
+
+    /// The account whose list has `device`, if any.
+    fn owner_of(&self, device: &PeerId) -> Option<PeerId> {
+        self.accounts
+            .iter()
+            .find(|(_, certs)| certs.iter().any(|c| c.device == *device))
+            .map(|(account, _)| *account)
+    }
+
+    /// Holds `cert` back if its device belongs to another account, returns
+    /// whether it did.
+    fn dispute(&mut self, cert: &DeviceCertificate) -> bool {
+        if self.owner_of(&cert.device).is_none_or(|owner| owner == cert.account) {
+            return false;
+        }
+        self.disputed.retain(|c| c.device != cert.device || c.account != cert.account);
+        self.disputed.push(cert.clone());
+        true
+    }
+
+    /// Records a certificate learned from a message, returns `false` if it
+    /// was known, the device has been removed or another account has it.
+    pub fn add_certificate(&mut self, cert: DeviceCertificate) -> bool {
+        if self.removed.contains(&cert.device) || self.dispute(&cert) {
+            return false;
+        }
+        let certs = self.accounts.entry(cert.account).or_default();
+        if certs.iter().any(|c| c.device == cert.device) {
+            return false;
+        }
+        certs.push(cert);
+        true
+    }
+
+    /// Applies a verified device list. The account's own list replaces what
+    /// we had and is the only way to remove devices, lists from its other
+    /// devices can only add to it. Devices another account has are held in
+    /// `disputed`, returns whether there were any.
+    pub fn apply_device_list(&mut self, sender: &PeerId, account: PeerId, certs: Vec<DeviceCertificate>) -> bool {
+        let disputed = self.disputed.len();
+        if *sender != account {
+            for cert in certs {
+                self.add_certificate(cert);
+            }
+            return self.disputed.len() > disputed;
+        }
+
+        let certs: Vec<_> = certs.into_iter().filter(|c| !self.dispute(c)).collect();
+
+        if let Some(known) = self.accounts.get(&account) {
+            for old in known {
+                if !certs.iter().any(|c| c.device == old.device) {
+                    self.removed.insert(old.device);
+                }
+            }
+        }
+        for cert in &certs {
+            self.removed.remove(&cert.device);
+        }
+        self.accounts.insert(account, certs);
+        self.disputed.len() > disputed
+    }
+
+    /// Moves `device` to the account of its disputed certificate, returns
+    /// that account.
+    pub fn confirm_move(&mut self, device: &PeerId) -> Option<PeerId> {
+        let i = self.disputed.iter().position(|c| c.device == *device)?;
+        let cert = self.disputed.remove(i);
+        self.disputed.retain(|c| c.device != *device);
+
+        for certs in self.accounts.values_mut() {
+            certs.retain(|c| c.device != *device);
+        }
+        self.removed.remove(device);
+        let account = cert.account;
+        self.accounts.entry(account).or_default().push(cert);
+        Some(account)
+    }
+}
+
+pub fn load_devices(
+    key: &[u8; 32],
+) -> Result<DeviceStore, Box<dyn std::error::Error>> {
+    let path = devices_path();
+
+    if !path.exists() {
+        return Ok(DeviceStore::default());
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    Ok(serde_json::from_slice(&decrypted)?)
+}
+
+pub fn save_devices(
+    store: &DeviceStore,
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let json = serde_json::to_vec(store)?;
+    // A crash mid write must not take every device certificate with it
+    let path = devices_path();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encrypt(&json, key))?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Loads the store, applies `update` and writes it back under `DEVICE_LOCK`.
+pub fn update_devices<T>(
+    key: &[u8; 32],
+    update: impl FnOnce(&mut DeviceStore) -> T,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let _guard = DEVICE_LOCK.lock().unwrap();
+    let mut store = load_devices(key)?;
+    let result = update(&mut store);
+    save_devices(&store, key)?;
+    Ok(result)
+}
+
+fn devices_path() -> PathBuf {
+    let mut path = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    path.push("devices.enc");
+    path
+}
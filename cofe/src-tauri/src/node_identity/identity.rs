@@ -45,3 +45,19 @@ pub fn load_or_create_identity() -> libp2p::identity::Keypair {
     return identity::Keypair::from(ed);
 }
 
+
+/// Hex encoded secret of `keypair`, the form it is kept in the keyring.
+pub fn export_identity(keypair: &identity::Keypair) -> Result<String, String> {
+    let ed = keypair.clone().try_into_ed25519().map_err(|e| e.to_string())?;
+    Ok(hex::encode(ed.secret().as_ref()))
+}
+
+/// Replaces the stored identity, it takes effect on the next start.
+pub fn import_identity(secret_hex: &str) -> Result<(), String> {
+    let bytes = hex::decode(secret_hex).map_err(|e| e.to_string())?;
+    SecretKey::try_from_bytes(bytes).map_err(|e| e.to_string())?;
+
+    Entry::new("my_app", "libp2p_identity")
+        .and_then(|entry| entry.set_password(secret_hex))
+        .map_err(|e| e.to_string())
+}
@@ -1,2 +1,4 @@
 pub mod identity;
-pub mod peers;
\ No newline at end of file
+pub mod peers;
+pub mod devices;
+pub mod contacts;
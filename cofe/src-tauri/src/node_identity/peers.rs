@@ -1,29 +1,176 @@
 use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
 
 use libp2p::PeerId;
+use serde::Serialize;
 use tauri::Manager;
-use tokio::fs as async_fs;
+use tokio::{fs as async_fs, io::AsyncWriteExt};
 
 use crate::{PeerStore, StoredPeer};
 
-async fn save_peers_to_disk(
+const BACKOFF_BASE_SECS: i64 = 60;
+const BACKOFF_MAX_SECS: i64 = 24 * 60 * 60;
+/// Days after which a peer's record counts for half as much.
+const SCORE_HALF_LIFE_DAYS: f64 = 7.0;
+
+/// Connection quality shown next to a peer in the UI.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionQuality {
+    Good,
+    Fair,
+    Poor,
+    /// Nothing has been sent to or received from the peer yet.
+    Unknown,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PeerReputation {
+    pub peer_id: String,
+    pub score: f64,
+    pub quality: ConnectionQuality,
+    pub last_seen: i64,
+    pub success: u32,
+    pub fail: u32,
+    pub backing_off: bool,
+}
+
+impl StoredPeer {
+    pub fn new(peer: PeerId) -> Self {
+        StoredPeer {
+            peer_id: peer.to_string(),
+            addrs: vec![],
+            last_seen: chrono::Utc::now().timestamp(),
+            success: 0,
+            fail: 0,
+            fail_streak: 0,
+            last_fail: 0,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        self.success = self.success.saturating_add(1);
+        self.fail_streak = 0;
+        self.last_seen = now;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.fail = self.fail.saturating_add(1);
+        self.fail_streak = self.fail_streak.saturating_add(1);
+        self.last_fail = chrono::Utc::now().timestamp();
+    }
+
+    /// Between 0 and 1, the smoothed success rate faded by how long ago the
+    /// peer was last seen. A peer with no history scores 0.5.
+    pub fn score(&self, now: i64) -> f64 {
+        let rate = (self.success as f64 + 1.0) / (self.success as f64 + self.fail as f64 + 2.0);
+        let age_days = (now - self.last_seen).max(0) as f64 / (24.0 * 60.0 * 60.0);
+        rate * 0.5f64.powf(age_days / SCORE_HALF_LIFE_DAYS)
+    }
+
+    /// Whether the peer failed too recently to be worth dialing again, the
+    /// wait doubles with every failure in a row.
+    pub fn backing_off(&self, now: i64) -> bool {
+        if self.fail_streak == 0 {
+            return false;
+        }
+        let wait = BACKOFF_BASE_SECS
+            .saturating_mul(1 << (self.fail_streak - 1).min(20))
+            .min(BACKOFF_MAX_SECS);
+        now < self.last_fail.saturating_add(wait)
+    }
+
+    pub fn reputation(&self, now: i64) -> PeerReputation {
+        let score = self.score(now);
+        let quality = match score {
+            _ if self.success == 0 && self.fail == 0 => ConnectionQuality::Unknown,
+            s if s >= 0.7 => ConnectionQuality::Good,
+            s if s >= 0.4 => ConnectionQuality::Fair,
+            _ => ConnectionQuality::Poor,
+        };
+
+        PeerReputation {
+            peer_id: self.peer_id.clone(),
+            score,
+            quality,
+            last_seen: self.last_seen,
+            success: self.success,
+            fail: self.fail,
+            backing_off: self.backing_off(now),
+        }
+    }
+}
+
+impl PeerStore {
+    /// Counts a dial or request outcome towards `peer`'s reputation.
+    pub async fn record_outcome(&self, peer: PeerId, ok: bool) {
+        let mut peers = self.peers.write().await;
+        let entry = peers.entry(peer).or_insert_with(|| StoredPeer::new(peer));
+        if ok {
+            entry.record_success();
+        } else {
+            entry.record_failure();
+        }
+    }
+
+    /// Stored peers worth dialing, best first, leaving out those backing off.
+    pub async fn dial_order(&self) -> Vec<(PeerId, Vec<String>)> {
+        let now = chrono::Utc::now().timestamp();
+        let peers = self.peers.read().await;
+
+        let mut ranked: Vec<(f64, PeerId, Vec<String>)> = peers
+            .iter()
+            .filter(|(_, peer)| !peer.addrs.is_empty() && !peer.backing_off(now))
+            .map(|(id, peer)| (peer.score(now), *id, peer.addrs.clone()))
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        ranked.into_iter().map(|(_, id, addrs)| (id, addrs)).collect()
+    }
+
+    pub async fn reputations(&self) -> Vec<PeerReputation> {
+        let now = chrono::Utc::now().timestamp();
+        let mut all: Vec<PeerReputation> = self.peers.read().await.values().map(|p| p.reputation(now)).collect();
+        all.sort_by(|a, b| b.score.total_cmp(&a.score));
+        all
+    }
+}
+
+/// Writes the peer store to disk, dropping peers not seen for `ttl_days`
+/// first. Goes through a temp file so a crash mid-write keeps the old one.
+pub async fn save_peers_to_disk(
     app: &tauri::AppHandle,
     peer_store: Arc<PeerStore>,
-) {
+    ttl_days: u64,
+) -> std::io::Result<()> {
     let path = peers_file_path(app);
 
-    let peers = peer_store.peers.read().await;
+    let raw: HashMap<String, StoredPeer> = {
+        let mut peers = peer_store.peers.write().await;
+        let cutoff = stale_cutoff(ttl_days);
+        peers.retain(|_, peer| peer.last_seen >= cutoff);
 
-    let raw: HashMap<String, StoredPeer> = peers
-        .iter()
-        .map(|(id, peer)| (id.to_string(), peer.clone()))
-        .collect();
+        peers
+            .iter()
+            .map(|(id, peer)| (id.to_string(), peer.clone()))
+            .collect()
+    };
 
-    if let Ok(json) = serde_json::to_string_pretty(&raw) {
-        let _ = async_fs::write(path, json).await;
-    }
+    let json = serde_json::to_string_pretty(&raw)?;
+    let tmp = path.with_extension("json.tmp");
+    let mut file = async_fs::File::create(&tmp).await?;
+    file.write_all(json.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    async_fs::rename(tmp, path).await
 }
 
+/// Unix time in seconds before which a peer counts as gone.
+fn stale_cutoff(ttl_days: u64) -> i64 {
+    let ttl = ttl_days.saturating_mul(24 * 60 * 60).min(i64::MAX as u64) as i64;
+    chrono::Utc::now().timestamp().saturating_sub(ttl)
+}
 
 fn peers_file_path(app: &tauri::AppHandle) -> PathBuf {
     let mut dir = app.path().resolve("p2p", tauri::path::BaseDirectory::AppData).expect("Failed to resolve app data dir");
@@ -32,7 +179,7 @@ fn peers_file_path(app: &tauri::AppHandle) -> PathBuf {
     dir
 }
 
-pub fn load_peers_from_disk(app: &tauri::AppHandle) -> HashMap<PeerId, StoredPeer> {
+pub fn load_peers_from_disk(app: &tauri::AppHandle, ttl_days: u64) -> HashMap<PeerId, StoredPeer> {
     let path = peers_file_path(app);
 
     if !path.exists() {
@@ -55,7 +202,8 @@ pub fn load_peers_from_disk(app: &tauri::AppHandle) -> HashMap<PeerId, StoredPee
         }
     };
 
-    raw.into_iter().filter_map(|(peer_id, peer)|{
+    let cutoff = stale_cutoff(ttl_days);
+    raw.into_iter().filter(|(_, peer)| peer.last_seen >= cutoff).filter_map(|(peer_id, peer)|{
         peer_id.parse::<PeerId>().ok().map(|id| (id, peer))
     }).collect()
 }
\ No newline at end of file
@@ -1,14 +1,89 @@
-use libp2p::PeerId;
+use libp2p::{kad::QueryId, request_response::OutboundRequestId, swarm::ConnectionId, PeerId};
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::{message::message::ChatEnvelope, p2p::command::FindNodeReply, security::x3dh::PreKeyBundle};
+
+pub const MAX_SEND_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct PendingChat {
+    pub peer: PeerId,
+    pub envelope: ChatEnvelope,
+    pub attempt: u32,
+}
 
 pub struct Agent {
     pub node_list: HashMap<PeerId, String>,
+    pub pending_chats: HashMap<OutboundRequestId, PendingChat>,
+    pub retry_queue: Vec<(Instant, PendingChat)>,
+    pub prekey_bundle: Option<PreKeyBundle>,
+    pub prekey_requests: HashMap<OutboundRequestId, PeerId>,
+    pub file_requests: HashMap<OutboundRequestId, String>,
+    /// Callers waiting on a `FindNode` lookup.
+    pub find_queries: HashMap<QueryId, FindNodeReply>,
+    /// Bootstrap dials still in progress.
+    pub bootstrap_dials: HashSet<ConnectionId>,
+    pub bootstrapped: bool,
+    pub bootstrap_query: Option<QueryId>,
+    pub last_bootstrap: Option<i64>,
 }
 
 impl Agent {
     pub fn new() -> Self {
         Self {
             node_list: HashMap::new(),
+            pending_chats: HashMap::new(),
+            retry_queue: Vec::new(),
+            prekey_bundle: None,
+            prekey_requests: HashMap::new(),
+            file_requests: HashMap::new(),
+            find_queries: HashMap::new(),
+            bootstrap_dials: HashSet::new(),
+            bootstrapped: false,
+            bootstrap_query: None,
+            last_bootstrap: None,
         }
     }
+
+    pub fn track_chat(&mut self, request_id: OutboundRequestId, pending: PendingChat) {
+        self.pending_chats.insert(request_id, pending);
+    }
+
+    pub fn take_pending(&mut self, request_id: &OutboundRequestId) -> Option<PendingChat> {
+        self.pending_chats.remove(request_id)
+    }
+
+    /// Queue another attempt with exponential backoff. Returns `false` once
+    /// the message has used up all of its attempts.
+    pub fn schedule_retry(&mut self, pending: PendingChat) -> bool {
+        if pending.attempt >= MAX_SEND_ATTEMPTS {
+            return false;
+        }
+
+        let delay = RETRY_BASE_DELAY * 2u32.pow(pending.attempt - 1);
+        self.retry_queue.push((Instant::now() + delay, pending));
+        true
+    }
+
+    pub fn prekeys_requested(&self, peer: &PeerId) -> bool {
+        self.prekey_requests.values().any(|p| p == peer)
+    }
+
+    /// A message fanned out to several devices shares its id, so the peer counts too.
+    pub fn is_in_flight(&self, peer: &PeerId, id: &str) -> bool {
+        self.pending_chats.values().any(|p| p.peer == *peer && p.envelope.id == id)
+            || self.retry_queue.iter().any(|(_, p)| p.peer == *peer && p.envelope.id == id)
+    }
+
+    pub fn due_retries(&mut self, now: Instant) -> Vec<PendingChat> {
+        let (due, waiting): (Vec<_>, Vec<_>) = self
+            .retry_queue
+            .drain(..)
+            .partition(|(at, _)| *at <= now);
+
+        self.retry_queue = waiting;
+        due.into_iter().map(|(_, pending)| pending).collect()
+    }
 }
@@ -1,8 +1,11 @@
-use crate::message::message::{GreetRequest, GreetResponse};
+use std::convert::Infallible;
+
+use crate::message::message::{FileRequest, FileResponse, GreetRequest, GreetResponse};
 use libp2p::kad::RoutingUpdate;
 use libp2p::request_response::OutboundRequestId;
 use libp2p::swarm::NetworkBehaviour;
-use libp2p::{identify, kad, request_response, Multiaddr, PeerId};
+use libp2p::{gossipsub, identify, kad, request_response, Multiaddr, PeerId};
+use libp2p_allow_block_list::{self as allow_block_list, BlockedPeers};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "Event")]
@@ -10,6 +13,10 @@ pub(crate) struct Behaviour {
     pub identify: identify::Behaviour,
     pub kad: kad::Behaviour<kad::store::MemoryStore>,
     pub rr: request_response::cbor::Behaviour<GreetRequest, GreetResponse>,
+    pub gossipsub: gossipsub::Behaviour,
+    pub file: request_response::cbor::Behaviour<FileRequest, FileResponse>,
+    /// Refuses and closes connections to peers the user blocked.
+    pub block: allow_block_list::Behaviour<BlockedPeers>,
 }
 
 impl Behaviour {
@@ -17,11 +24,16 @@ impl Behaviour {
         kad: kad::Behaviour<kad::store::MemoryStore>,
         identify: identify::Behaviour,
         rr: request_response::cbor::Behaviour<GreetRequest, GreetResponse>,
+        gossipsub: gossipsub::Behaviour,
+        file: request_response::cbor::Behaviour<FileRequest, FileResponse>,
     ) -> Self {
         Self {
             identify: identify,
             kad: kad,
             rr: rr,
+            gossipsub: gossipsub,
+            file: file,
+            block: allow_block_list::Behaviour::default(),
         }
     }
 
@@ -55,6 +67,8 @@ pub(crate) enum Event {
     Identify(identify::Event),
     Kad(kad::Event),
     RequestResponse(request_response::Event<GreetRequest, GreetResponse>),
+    Gossipsub(gossipsub::Event),
+    File(request_response::Event<FileRequest, FileResponse>),
 }
 
 impl From<identify::Event> for Event {
@@ -74,3 +88,22 @@ impl From<request_response::Event<GreetRequest, GreetResponse>> for Event {
         Self::RequestResponse(value)
     }
 }
+
+
+impl From<gossipsub::Event> for Event {
+    fn from(value: gossipsub::Event) -> Self {
+        Self::Gossipsub(value)
+    }
+}
+
+impl From<request_response::Event<FileRequest, FileResponse>> for Event {
+    fn from(value: request_response::Event<FileRequest, FileResponse>) -> Self {
+        Self::File(value)
+    }
+}
+
+impl From<Infallible> for Event {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
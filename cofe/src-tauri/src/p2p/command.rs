@@ -1,15 +1,51 @@
-use libp2p::{PeerId, Swarm};
+use std::time::Instant;
 
-use crate::{ ChatMessage, message::message::GreetRequest, p2p::behaviour::Behaviour as AgentBehaviour};
+use libp2p::{kad, request_response::ResponseChannel, PeerId, Swarm};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::{ message::{group::group_store::group_topic, message::{ChatEnvelope, DeliveryStatus, DeviceCertificate, FileRequest, FileResponse, GreetRequest, GreetResponse, GroupEnvelope, GroupInvite}}, security::x3dh::PreKeyBundle, p2p::{agent::{Agent, PendingChat}, behaviour::Behaviour as AgentBehaviour, event::P2PEvent}};
+
+
+/// A peer returned by a Kademlia lookup.
+#[derive(Serialize, Clone, Debug)]
+pub struct DiscoveredPeer {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+impl From<kad::PeerInfo> for DiscoveredPeer {
+    fn from(info: kad::PeerInfo) -> Self {
+        Self {
+            peer_id: info.peer_id.to_string(),
+            addrs: info.addrs.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+pub type FindNodeReply = oneshot::Sender<Result<Vec<DiscoveredPeer>, String>>;
 
 pub enum P2PCommand {
     SendGreet { peer: PeerId, msg: String },
-    SendChat { peer: PeerId, msg: ChatMessage },
-    FindNode { peer: PeerId },
+    SendChat { peer: PeerId, envelope: ChatEnvelope },
+    /// Answered on `reply` once the `GetClosestPeers` query finishes.
+    FindNode { peer: PeerId, reply: FindNodeReply },
+    RequestPreKeys { peer: PeerId },
+    PublishPreKeys { bundle: PreKeyBundle },
+    JoinGroup { group_id: String },
+    LeaveGroup { group_id: String },
+    PublishGroup { envelope: GroupEnvelope },
+    SendGroupInvite { peer: PeerId, invite: GroupInvite },
+    RequestFileChunk { peer: PeerId, file_id: String, offset: u64 },
+    RespondFile { channel: ResponseChannel<FileResponse>, response: FileResponse },
+    RespondChat { channel: ResponseChannel<GreetResponse>, response: GreetResponse },
+    RequestDeviceLink { peer: PeerId, consent: Vec<u8> },
+    SendDeviceList { peer: PeerId, certs: Vec<DeviceCertificate> },
+    BlockPeer { peer: PeerId },
+    UnblockPeer { peer: PeerId },
 }
 
-pub fn handle_command(cmd: P2PCommand, swarm: &mut Swarm<AgentBehaviour>) {
+pub async fn handle_command(cmd: P2PCommand, swarm: &mut Swarm<AgentBehaviour>, agent: &mut Agent, event_tx: &mpsc::Sender<P2PEvent>) {
     match cmd {
         P2PCommand::SendGreet { peer, msg } => {
             swarm
@@ -17,18 +53,110 @@ pub fn handle_command(cmd: P2PCommand, swarm: &mut Swarm<AgentBehaviour>) {
                 .rr
                 .send_request(&peer, GreetRequest::Syn { message: msg });
         },
-        P2PCommand::SendChat { peer, msg } => {
-            log::info!("send chat: {msg:?}");
+        P2PCommand::SendChat { peer, envelope } => {
+            if agent.is_in_flight(&peer, &envelope.id) {
+                return;
+            }
+            log::info!("send chat: {} to {peer}", envelope.id);
+            let _ = event_tx.send(P2PEvent::MessageStatus { peer, id: envelope.id.clone(), status: DeliveryStatus::Pending }).await;
+            send_chat(swarm, agent, PendingChat { peer, envelope, attempt: 0 });
+        },
+        P2PCommand::FindNode { peer, reply } => {
+            let query_id = swarm
+                .behaviour_mut()
+                .kad
+                .get_closest_peers(peer);
+            agent.find_queries.insert(query_id, reply);
+        }
+        P2PCommand::RequestPreKeys { peer } => {
+            if agent.prekeys_requested(&peer) {
+                return;
+            }
+            let request_id = swarm
+                .behaviour_mut()
+                .rr
+                .send_request(&peer, GreetRequest::PreKeyRequest);
+            agent.prekey_requests.insert(request_id, peer);
+        }
+        P2PCommand::PublishPreKeys { bundle } => {
+            agent.prekey_bundle = Some(bundle);
+        }
+        P2PCommand::JoinGroup { group_id } => {
+            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&group_topic(&group_id)) {
+                log::warn!("failed to subscribe to group {group_id}: {e:?}");
+            }
+        }
+        P2PCommand::LeaveGroup { group_id } => {
+            swarm.behaviour_mut().gossipsub.unsubscribe(&group_topic(&group_id));
+        }
+        P2PCommand::PublishGroup { envelope } => {
+            let topic = group_topic(&envelope.group_id);
+            match serde_json::to_vec(&envelope) {
+                Ok(data) => {
+                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                        log::warn!("failed to publish group message {}: {e:?}", envelope.id);
+                    }
+                }
+                Err(e) => log::warn!("failed to encode group message {}: {e}", envelope.id),
+            }
+        }
+        P2PCommand::SendGroupInvite { peer, invite } => {
             swarm
                 .behaviour_mut()
                 .rr
-                .send_request(&peer, GreetRequest::Chat{ message: msg });
-        },
-        P2PCommand::FindNode { peer } => {
+                .send_request(&peer, GreetRequest::GroupInvite { invite });
+        }
+        P2PCommand::RequestFileChunk { peer, file_id, offset } => {
+            let request_id = swarm
+                .behaviour_mut()
+                .file
+                .send_request(&peer, FileRequest::Chunk { file_id: file_id.clone(), offset });
+            agent.file_requests.insert(request_id, file_id);
+        }
+        P2PCommand::RespondFile { channel, response } => {
+            if swarm.behaviour_mut().file.send_response(channel, response).is_err() {
+                log::warn!("failed to send file response, channel closed");
+            }
+        }
+        P2PCommand::RespondChat { channel, response } => {
+            if swarm.behaviour_mut().rr.send_response(channel, response).is_err() {
+                log::warn!("failed to answer chat, channel closed");
+            }
+        }
+        P2PCommand::RequestDeviceLink { peer, consent } => {
             swarm
                 .behaviour_mut()
-                .kad
-                .get_closest_peers(peer);
+                .rr
+                .send_request(&peer, GreetRequest::LinkRequest { consent });
+        }
+        P2PCommand::SendDeviceList { peer, certs } => {
+            swarm
+                .behaviour_mut()
+                .rr
+                .send_request(&peer, GreetRequest::DeviceList { certs });
+        }
+        P2PCommand::BlockPeer { peer } => {
+            swarm.behaviour_mut().block.block_peer(peer);
         }
+        P2PCommand::UnblockPeer { peer } => {
+            swarm.behaviour_mut().block.unblock_peer(peer);
+        }
+    }
+}
+
+//@ Resend chats whose backoff has elapsed
+pub fn retry_pending_chats(swarm: &mut Swarm<AgentBehaviour>, agent: &mut Agent) {
+    for pending in agent.due_retries(Instant::now()) {
+        log::info!("retry chat {} to {} (attempt {})", pending.envelope.id, pending.peer, pending.attempt + 1);
+        send_chat(swarm, agent, pending);
     }
-}
\ No newline at end of file
+}
+
+fn send_chat(swarm: &mut Swarm<AgentBehaviour>, agent: &mut Agent, mut pending: PendingChat) {
+    pending.attempt += 1;
+    let request_id = swarm
+        .behaviour_mut()
+        .rr
+        .send_request(&pending.peer, GreetRequest::Chat{ envelope: pending.envelope.clone() });
+    agent.track_chat(request_id, pending);
+}
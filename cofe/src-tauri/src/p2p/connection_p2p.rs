@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::time::Duration;
 
@@ -7,59 +8,41 @@ use libp2p::{
         transport::{upgrade, OrTransport},
         Transport,
     },
-    identify, identity, noise, request_response,
+    gossipsub, identify, identity, noise, request_response,
+    multiaddr::Protocol, swarm::{dial_opts::DialOpts, ConnectionId},
     tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
 };
+use tracing::warn;
 
 use crate::config::{Config, IpVersion};
 use crate::{
-    message::message::{GreetRequest, GreetResponse},
+    message::message::{FileRequest, FileResponse, GreetRequest, GreetResponse},
     p2p::{
         behaviour::{Behaviour as AgentBehaviour},
     },
 };
 
-pub struct BootstrapNode {
-    pub addr: Multiaddr,
-    pub peer_id: PeerId,
-}
+/// Lookups give up after this and report what they found so far.
+pub const KAD_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct P2P {
     local_key: identity::Keypair,
     cfg: Config,
-    bootstrap_node_addr: Option<BootstrapNode>,
+    bootstrap_nodes: Vec<Multiaddr>,
 }
 
 impl P2P {
       pub fn new(local_key: identity::Keypair, cfg: Config) -> Result<Self, Box<dyn Error>> {
 
-        let bootstrap = match (
-            &cfg.network.bootstrap_ip,
-            cfg.network.bootstrap_port,
-            &cfg.network.bootstrap_peer_id,
-        ) {
-            (Some(ip), Some(port), Some(peer_id_str)) => {
-                let peer_id = peer_id_str.parse::<PeerId>()?;
-
-                let addr_str = match cfg.network.ip_version {
-                    IpVersion::Ipv4 => {
-                        format!("/ip4/{}/tcp/{}/ws/p2p/{}", ip, port, peer_id)
-                    }
-                    IpVersion::Ipv6 => {
-                        format!("/ip6/{}/tcp/{}/ws/p2p/{}", ip, port, peer_id)
-                    }
-                };
-
-                let addr = addr_str.parse::<Multiaddr>()?;
-                Some(BootstrapNode { addr, peer_id })
-            }
-            _ => None,
-        };
+        let bootstrap_nodes = cfg.network.bootstrap_nodes()?;
+        if bootstrap_nodes.is_empty() {
+            warn!("no bootstrap node configured, other peers cannot be found until one is set");
+        }
 
         Ok(Self {
             local_key,
             cfg,
-            bootstrap_node_addr: bootstrap,
+            bootstrap_nodes,
         })
     }
 
@@ -79,10 +62,15 @@ impl P2P {
                     .multiplex(yamux::Config::default())
                     .boxed()
             })?
+            // Resolves `/dns*` and `/dnsaddr` bootstrap addresses
+            .with_dns()?
             .with_behaviour(|keypair| {
                 let local_peer_id = PeerId::from(keypair.clone().public());
 
-                let kad_config = kad::Config::default();
+                let mut kad_config = kad::Config::default();
+                // Scheduled from the swarm loop instead, see `refresh_routing`
+                kad_config.set_periodic_bootstrap_interval(None);
+                kad_config.set_query_timeout(KAD_QUERY_TIMEOUT);
                 let kad_memory = kad::store::MemoryStore::new(local_peer_id);
                 let kad = kad::Behaviour::with_config(local_peer_id, kad_memory, kad_config);
 
@@ -102,11 +90,30 @@ impl P2P {
                         rr_config,
                     );
 
-                AgentBehaviour {
+                let file_protocol = StreamProtocol::new("/agent/file/1.0.0");
+                let file_behavior =
+                    request_response::cbor::Behaviour::<FileRequest, FileResponse>::new(
+                        [(file_protocol, request_response::ProtocolSupport::Full)],
+                        request_response::Config::default(),
+                    );
+
+                let gossipsub_config = gossipsub::ConfigBuilder::default()
+                    .heartbeat_interval(Duration::from_secs(10))
+                    .validation_mode(gossipsub::ValidationMode::Strict)
+                    .build()?;
+                let gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(keypair.clone()),
+                    gossipsub_config,
+                )?;
+
+                Ok(AgentBehaviour {
                     identify: identify,
                     kad: kad,
                     rr: rr_behavior,
-                }
+                    gossipsub: gossipsub,
+                    file: file_behavior,
+                    block: libp2p_allow_block_list::Behaviour::default(),
+                })
             })?
             .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(30)))
             .build();
@@ -127,11 +134,49 @@ impl P2P {
             swarm.listen_on(listen_addr.parse()?)?;
 
 
-        if let Some(bootstrap) = &self.bootstrap_node_addr {
-            swarm.dial(bootstrap.addr.clone())?;
+        Ok(swarm)
+    }
+
+    /// Dials every bootstrap node at once, so an unreachable one only costs
+    /// its own dial. Returns the pending connections, the first to come up
+    /// triggers `kad.bootstrap()`. Nodes we are connected to are skipped.
+    pub fn dial_bootstrap_nodes(&self, swarm: &mut Swarm<AgentBehaviour>) -> HashSet<ConnectionId> {
+        let mut dials = HashSet::new();
+
+        for addr in &self.bootstrap_nodes {
+            let opts = match addr.iter().last() {
+                Some(Protocol::P2p(peer_id)) if swarm.is_connected(&peer_id) => continue,
+                Some(Protocol::P2p(peer_id)) => {
+                    swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+                    DialOpts::peer_id(peer_id).addresses(vec![addr.clone()]).build()
+                }
+                _ => DialOpts::unknown_peer_id().address(addr.clone()).build(),
+            };
+
+            let connection_id = opts.connection_id();
+            match swarm.dial(opts) {
+                Ok(()) => {
+                    dials.insert(connection_id);
+                }
+                Err(e) => warn!("failed to dial bootstrap node {addr}: {e}"),
+            }
         }
 
+        dials
+    }
 
-        Ok(swarm)
+    /// Whether none of the bootstrap nodes with a known PeerId is connected.
+    /// Without any such node this cannot be told and is `false`.
+    pub fn bootstrap_lost(&self, swarm: &Swarm<AgentBehaviour>) -> bool {
+        let mut known = self
+            .bootstrap_nodes
+            .iter()
+            .filter_map(|addr| match addr.iter().last() {
+                Some(Protocol::P2p(peer_id)) => Some(peer_id),
+                _ => None,
+            })
+            .peekable();
+
+        known.peek().is_some() && !known.any(|peer_id| swarm.is_connected(&peer_id))
     }
 }
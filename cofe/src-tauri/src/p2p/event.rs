@@ -1,17 +1,32 @@
-use libp2p::{PeerId, Swarm, identify, kad, request_response, swarm::SwarmEvent};
+use libp2p::{PeerId, Swarm, gossipsub, identify, kad, request_response, swarm::SwarmEvent};
 use tokio::sync::mpsc;
 use tracing::{info, warn};
 
 use crate::{
-    ChatMessage, PeerStore, StoredPeer, message::message::{GreetRequest, GreetResponse}, p2p::behaviour::{Behaviour as AgentBehaviour, Event as AgentEvent}
+    PeerStore, StoredPeer, message::{group::group_store::group_topic, message::{ChatEnvelope, DeliveryStatus, DeviceCertificate, FileRequest, FileResponse, GreetRequest, GreetResponse, GroupEnvelope, GroupInvite}}, security::{device::verify_device_list, e2e::verify_envelope, x3dh::{verify_bundle, PreKeyBundle}}, p2p::{agent::Agent, behaviour::{Behaviour as AgentBehaviour, Event as AgentEvent}, command::DiscoveredPeer, health::NetworkHealth}
 };
 
 pub enum P2PEvent {
     PeerDiscovered(PeerId),
-    MessageReceived { peer: PeerId, msg: ChatMessage },
+    /// Answered on `channel` once the message is stored, or could not be.
+    MessageReceived { peer: PeerId, envelope: ChatEnvelope, channel: request_response::ResponseChannel<GreetResponse> },
+    MessageStatus { peer: PeerId, id: String, status: DeliveryStatus },
+    /// The first connection to a peer came up.
+    PeerConnected(PeerId),
+    SecurityWarning { peer: PeerId, reason: String },
+    PreKeyBundle { peer: PeerId, bundle: PreKeyBundle },
+    GroupInvite { peer: PeerId, invite: GroupInvite },
+    GroupMessageReceived { envelope: GroupEnvelope },
+    FileChunkRequested { peer: PeerId, file_id: String, offset: u64, channel: request_response::ResponseChannel<FileResponse> },
+    FileChunkReceived { peer: PeerId, response: FileResponse },
+    FileTransferFailed { peer: PeerId, file_id: String, reason: String },
+    DeviceLinkRequested { peer: PeerId, consent: Vec<u8> },
+    /// Already verified, `account` is who the certificates belong to.
+    DeviceList { peer: PeerId, account: PeerId, certs: Vec<DeviceCertificate> },
+    NetworkHealth(NetworkHealth),
 }
 
-pub async fn handle_swarm_event(event: SwarmEvent<AgentEvent>, swarm: &mut Swarm<AgentBehaviour>, peer_store: &PeerStore, event_tx: &mpsc::Sender<P2PEvent>) {
+pub async fn handle_swarm_event(event: SwarmEvent<AgentEvent>, swarm: &mut Swarm<AgentBehaviour>, agent: &mut Agent, peer_store: &PeerStore, event_tx: &mpsc::Sender<P2PEvent>) {
     match event {
          SwarmEvent::NewListenAddr {
                 listener_id,
@@ -24,15 +39,46 @@ pub async fn handle_swarm_event(event: SwarmEvent<AgentEvent>, swarm: &mut Swarm
                 num_established,
                 concurrent_dial_errors,
                 established_in,
-            } => info!("ConnectionEstablished: {peer_id:?} | {connection_id:?} | {endpoint:?} | {num_established:?} | {concurrent_dial_errors:?} | {established_in:?}"),
+            } => {
+                info!("ConnectionEstablished: {peer_id:?} | {connection_id:?} | {endpoint:?} | {num_established:?} | {concurrent_dial_errors:?} | {established_in:?}");
+                if endpoint.is_dialer() {
+                    peer_store.record_outcome(peer_id, true).await;
+                }
+                if agent.bootstrap_dials.remove(&connection_id) {
+                    // The resolved address, a dnsaddr one may not have named the peer
+                    swarm.behaviour_mut().kad.add_address(&peer_id, endpoint.get_remote_address().clone());
+                    if !agent.bootstrapped {
+                        match swarm.behaviour_mut().kad.bootstrap() {
+                            Ok(id) => {
+                                agent.bootstrapped = true;
+                                agent.bootstrap_query = Some(id);
+                            }
+                            Err(e) => warn!("kademlia bootstrap failed: {e:?}"),
+                        }
+                    }
+                }
+                // Once per peer, not for every extra connection to it
+                if num_established.get() == 1 {
+                    let _ = event_tx.send(P2PEvent::PeerConnected(peer_id)).await;
+                }
+            }
             SwarmEvent::Dialing { peer_id, connection_id } => info!("Dialing: {peer_id:?} | {connection_id}"),
+            SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                warn!("OutgoingConnectionError: {peer_id:?} | {connection_id} | {error}");
+                if let Some(peer) = peer_id {
+                    peer_store.record_outcome(peer, false).await;
+                }
+                if agent.bootstrap_dials.remove(&connection_id) && agent.bootstrap_dials.is_empty() && !agent.bootstrapped {
+                    warn!("no bootstrap node reachable, relying on known peers");
+                }
+            }
             SwarmEvent::Behaviour(AgentEvent::Identify(event)) => match event {
                 identify::Event::Sent { connection_id, peer_id } => info!("Sent: {connection_id} | {peer_id}"),
                 identify::Event::Pushed { connection_id, peer_id, info } => info!("Pushed: {connection_id} | {peer_id} | {info:?}"),
                 identify::Event::Received { connection_id: _, peer_id, info } => {
                     let mut peers = peer_store.peers.write().await;
 
-                    let entry = peers.entry(peer_id).or_insert(StoredPeer { peer_id: peer_id.to_string(), addrs: vec![], last_seen: chrono::Utc::now().timestamp(), success: 0, fail: 0 });
+                    let entry = peers.entry(peer_id).or_insert_with(|| StoredPeer::new(peer_id));
                     entry.addrs = info.listen_addrs.iter().map(|a| a.to_string()).collect();
 
                     entry.last_seen = chrono::Utc::now().timestamp();
@@ -46,16 +92,96 @@ pub async fn handle_swarm_event(event: SwarmEvent<AgentEvent>, swarm: &mut Swarm
                             info!("request_response::Event::Message::Request -> PeerID: {peer} | RequestID: {request_id} | RequestMessage: {request:?}");
                             match request {
                                 GreetRequest::Syn { message } => {}
-                                GreetRequest::Chat { message } => {
-                                    let _ = event_tx.send(P2PEvent::MessageReceived { peer, msg: message }).await;
+                                GreetRequest::Chat { envelope } => {
+                                    let id = envelope.id.clone();
+                                    match verify_envelope(&envelope, &peer, swarm.local_peer_id()) {
+                                        // Acked by `RespondChat` after the message is on disk
+                                        Ok(()) => {
+                                            let _ = event_tx.send(P2PEvent::MessageReceived { peer, envelope, channel }).await;
+                                        }
+                                        Err(reason) => {
+                                            warn!("rejected chat {id} from {peer}: {reason}");
+                                            let _ = event_tx.send(P2PEvent::SecurityWarning { peer, reason: reason.clone() }).await;
+                                            if swarm.behaviour_mut().send_response(channel, GreetResponse::Rejected { id, reason }).is_err() {
+                                                warn!("failed to answer chat from {peer}, channel closed");
+                                            }
+                                        }
+                                    }
+                                }
+                                GreetRequest::GroupInvite { invite } => {
+                                    let response = GreetResponse::Ack { message: invite.group_id.clone() };
+                                    let _ = event_tx.send(P2PEvent::GroupInvite { peer, invite }).await;
+                                    if swarm.behaviour_mut().send_response(channel, response).is_err() {
+                                        warn!("failed to ack group invite from {peer}, channel closed");
+                                    }
+                                }
+                                GreetRequest::LinkRequest { consent } => {
+                                    let _ = event_tx.send(P2PEvent::DeviceLinkRequested { peer, consent }).await;
+                                    if swarm.behaviour_mut().send_response(channel, GreetResponse::Ack { message: "link".into() }).is_err() {
+                                        warn!("failed to ack link request from {peer}, channel closed");
+                                    }
+                                }
+                                GreetRequest::DeviceList { certs } => {
+                                    match verify_device_list(&certs, &peer) {
+                                        Ok(account) => {
+                                            let _ = event_tx.send(P2PEvent::DeviceList { peer, account, certs }).await;
+                                        }
+                                        Err(reason) => {
+                                            warn!("bad device list from {peer}: {reason}");
+                                            let _ = event_tx.send(P2PEvent::SecurityWarning { peer, reason }).await;
+                                        }
+                                    }
+                                    if swarm.behaviour_mut().send_response(channel, GreetResponse::Ack { message: "devices".into() }).is_err() {
+                                        warn!("failed to ack device list from {peer}, channel closed");
+                                    }
+                                }
+                                GreetRequest::PreKeyRequest => {
+                                    let response = GreetResponse::PreKeys { bundle: agent.prekey_bundle.clone() };
+                                    if swarm.behaviour_mut().send_response(channel, response).is_err() {
+                                        warn!("failed to send prekeys to {peer}, channel closed");
+                                    }
                                 }
                             }
                         }
                         
                         request_response::Message::Response { request_id, response } => {
                             info!(" request_response::Event::Message::Response -> PeerID: {peer} | RequestID: {request_id} | ResponseMessage: {response:?}");
+                            peer_store.record_outcome(peer, true).await;
                             match response {
                                 GreetResponse::Ack { message } => {}
+                                GreetResponse::Delivered { id } => {
+                                    if agent.take_pending(&request_id).is_some() {
+                                        let _ = event_tx.send(P2PEvent::MessageStatus { peer, id, status: DeliveryStatus::Delivered }).await;
+                                    }
+                                }
+                                GreetResponse::Rejected { id, reason } => {
+                                    warn!("chat {id} rejected by {peer}: {reason}");
+                                    if agent.take_pending(&request_id).is_some() {
+                                        let _ = event_tx.send(P2PEvent::MessageStatus { peer, id, status: DeliveryStatus::Rejected }).await;
+                                    }
+                                }
+                                GreetResponse::Deferred { id, reason } => {
+                                    info!("chat {id} deferred by {peer}: {reason}");
+                                    // Still in the outbox, a later flush sends it again once retries run out
+                                    if let Some(pending) = agent.take_pending(&request_id) {
+                                        if !agent.schedule_retry(pending) {
+                                            let _ = event_tx.send(P2PEvent::MessageStatus { peer, id, status: DeliveryStatus::Failed }).await;
+                                        }
+                                    }
+                                }
+                                GreetResponse::PreKeys { bundle } => {
+                                    agent.prekey_requests.remove(&request_id);
+                                    match bundle.map(|b| verify_bundle(&b, &peer).map(|_| b)) {
+                                        Some(Ok(bundle)) => {
+                                            let _ = event_tx.send(P2PEvent::PreKeyBundle { peer, bundle }).await;
+                                        }
+                                        Some(Err(reason)) => {
+                                            warn!("bad prekey bundle from {peer}: {reason}");
+                                            let _ = event_tx.send(P2PEvent::SecurityWarning { peer, reason }).await;
+                                        }
+                                        None => info!("{peer} has no prekeys available, is it locked?"),
+                                    }
+                                }
                             }
                         }
                     }
@@ -64,16 +190,97 @@ pub async fn handle_swarm_event(event: SwarmEvent<AgentEvent>, swarm: &mut Swarm
                     warn!("request_response::Event::InboundFailure -> PeerID: {peer} | ConnectionID: {connection_id} | RequestID: {request_id} | Error: {error:?}")
                 }
                 request_response::Event::OutboundFailure { peer, connection_id, request_id, error } => {
-                    warn!("request_response::Event::OutboundFailure -> PeerID: {peer} | ConnectionID: {connection_id} | RequestID: {request_id} | Error: {error:?}")
+                    warn!("request_response::Event::OutboundFailure -> PeerID: {peer} | ConnectionID: {connection_id} | RequestID: {request_id} | Error: {error:?}");
+                    peer_store.record_outcome(peer, false).await;
+                    agent.prekey_requests.remove(&request_id);
+                    if let Some(pending) = agent.take_pending(&request_id) {
+                        let id = pending.envelope.id.clone();
+                        if !agent.schedule_retry(pending) {
+                            warn!("giving up on chat {id} to {peer}");
+                            let _ = event_tx.send(P2PEvent::MessageStatus { peer, id, status: DeliveryStatus::Failed }).await;
+                        }
+                    }
                 }
                 request_response::Event::ResponseSent { peer, connection_id, request_id } => {
                     info!("request_response::Event::ResponseSent -> PeerID: {peer} | ConnectionID: {connection_id} | RequestID: {request_id}")
                 }
             }
+            SwarmEvent::Behaviour(AgentEvent::File(event)) => match event {
+                request_response::Event::Message { peer, connection_id: _, message } => match message {
+                    request_response::Message::Request { request_id: _, request, channel } => match request {
+                        FileRequest::Chunk { file_id, offset } => {
+                            let _ = event_tx.send(P2PEvent::FileChunkRequested { peer, file_id, offset, channel }).await;
+                        }
+                    },
+                    request_response::Message::Response { request_id, response } => {
+                        agent.file_requests.remove(&request_id);
+                        peer_store.record_outcome(peer, true).await;
+                        let _ = event_tx.send(P2PEvent::FileChunkReceived { peer, response }).await;
+                    }
+                },
+                request_response::Event::OutboundFailure { peer, connection_id: _, request_id, error } => {
+                    warn!("file OutboundFailure -> PeerID: {peer} | RequestID: {request_id} | Error: {error:?}");
+                    peer_store.record_outcome(peer, false).await;
+                    if let Some(file_id) = agent.file_requests.remove(&request_id) {
+                        let _ = event_tx.send(P2PEvent::FileTransferFailed { peer, file_id, reason: error.to_string() }).await;
+                    }
+                }
+                request_response::Event::InboundFailure { peer, connection_id: _, request_id, error } => {
+                    warn!("file InboundFailure -> PeerID: {peer} | RequestID: {request_id} | Error: {error:?}")
+                }
+                request_response::Event::ResponseSent { .. } => {}
+            }
+            SwarmEvent::Behaviour(AgentEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message })) => {
+                let envelope = match serde_json::from_slice::<GroupEnvelope>(&message.data) {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        warn!("gossipsub::Event::Message -> undecodable message {message_id} via {propagation_source}: {e}");
+                        return;
+                    }
+                };
+
+                // Gossipsub already checked the publisher's signature, the envelope must agree with it
+                if message.source != Some(envelope.from) || message.topic != group_topic(&envelope.group_id).hash() {
+                    let reason = format!("group message {} claims sender {} but was published by {:?}", envelope.id, envelope.from, message.source);
+                    warn!("{reason}");
+                    let _ = event_tx.send(P2PEvent::SecurityWarning { peer: propagation_source, reason }).await;
+                    return;
+                }
+
+                let _ = event_tx.send(P2PEvent::GroupMessageReceived { envelope }).await;
+            }
+            SwarmEvent::Behaviour(AgentEvent::Gossipsub(event)) => {
+                info!("gossipsub::Event -> {event:?}")
+            }
             //@ Event dipicu ketika ada query
-            SwarmEvent::Behaviour(AgentEvent::Kad(kad::Event::OutboundQueryProgressed { id, result, stats: _, step:_ })) => {
-                if let kad::QueryResult::GetClosestPeers(Ok(ok)) = result {
-                    
+            SwarmEvent::Behaviour(AgentEvent::Kad(kad::Event::OutboundQueryProgressed { id, result, stats: _, step })) => {
+                match result {
+                    kad::QueryResult::GetClosestPeers(result) if step.last => {
+                        let Some(reply) = agent.find_queries.remove(&id) else {
+                            return;
+                        };
+                        let peers = match result {
+                            Ok(ok) => ok.peers,
+                            // Whatever was found before the deadline is still worth returning
+                            Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+                        };
+                        info!("Query {id} finished, found {} peers", peers.len());
+                        let _ = reply.send(Ok(peers.into_iter().map(DiscoveredPeer::from).collect()));
+                    }
+                    kad::QueryResult::Bootstrap(result) if agent.bootstrap_query == Some(id) => {
+                        if step.last {
+                            agent.bootstrap_query = None;
+                        }
+                        match result {
+                            Ok(ok) if ok.num_remaining == 0 => {
+                                info!("kademlia bootstrap finished");
+                                agent.last_bootstrap = Some(chrono::Utc::now().timestamp());
+                            }
+                            Ok(_) => {}
+                            Err(e) => warn!("kademlia bootstrap failed: {e:?}"),
+                        }
+                    }
+                    _ => {}
                 }
             }
         
@@ -94,13 +301,7 @@ pub async fn handle_swarm_event(event: SwarmEvent<AgentEvent>, swarm: &mut Swarm
                     info!("kad::Event::RoutingUpdated: {peer} | IsNewPeer: {is_new_peer} | Addresses: {addresses:?} | BucketRange: {bucket_range:?} | OldPeer: {old_peer:?}");
                     let mut peers = peer_store.peers.write().await;
 
-                    let entry = peers.entry(peer).or_insert(StoredPeer {
-                        peer_id: peer.to_string(),
-                        addrs: vec![],
-                        last_seen: chrono::Utc::now().timestamp(),
-                        success: 0,
-                        fail: 0,
-                    });
+                    let entry = peers.entry(peer).or_insert_with(|| StoredPeer::new(peer));
 
                     entry.addrs = addresses.iter().map(|a| a.to_string()).collect();
                     entry.last_seen = chrono::Utc::now().timestamp();
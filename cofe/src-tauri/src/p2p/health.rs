@@ -0,0 +1,70 @@
+use libp2p::Swarm;
+use serde::Serialize;
+
+use crate::p2p::{agent::Agent, behaviour::Behaviour as AgentBehaviour, connection_p2p::P2P};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    /// Connected, but to fewer peers than `min_connected_peers`.
+    Degraded,
+    Offline,
+}
+
+/// Sent to the UI as `network-health`.
+#[derive(Serialize, Clone, Debug)]
+pub struct NetworkHealth {
+    pub status: HealthStatus,
+    pub connected_peers: usize,
+    pub routing_table_peers: usize,
+    /// Unix seconds of the last finished Kademlia bootstrap.
+    pub last_bootstrap: Option<i64>,
+}
+
+pub fn network_health(swarm: &mut Swarm<AgentBehaviour>, agent: &Agent, min_peers: usize) -> NetworkHealth {
+    let connected_peers = swarm.connected_peers().count();
+    let routing_table_peers = swarm
+        .behaviour_mut()
+        .kad
+        .kbuckets()
+        .map(|bucket| bucket.num_entries())
+        .sum();
+
+    let status = match connected_peers {
+        0 => HealthStatus::Offline,
+        n if n < min_peers => HealthStatus::Degraded,
+        _ => HealthStatus::Healthy,
+    };
+
+    NetworkHealth {
+        status,
+        connected_peers,
+        routing_table_peers,
+        last_bootstrap: agent.last_bootstrap,
+    }
+}
+
+//@ Redial the bootstrap nodes when every peer or every bootstrap node is gone
+pub fn redial_bootstrap(swarm: &mut Swarm<AgentBehaviour>, agent: &mut Agent, p2p: &P2P) {
+    let lost = swarm.connected_peers().count() == 0 || p2p.bootstrap_lost(swarm);
+    if lost && agent.bootstrap_dials.is_empty() {
+        log::info!("no bootstrap node connected, dialing them again");
+        agent.bootstrap_dials = p2p.dial_bootstrap_nodes(swarm);
+        // Bootstrap again once one of them answers
+        agent.bootstrapped = false;
+    }
+}
+
+//@ Bootstrap Kademlia again, redialing the bootstrap nodes first if needed
+pub fn refresh_routing(swarm: &mut Swarm<AgentBehaviour>, agent: &mut Agent, p2p: &P2P) {
+    redial_bootstrap(swarm, agent, p2p);
+
+    if agent.bootstrap_query.is_some() {
+        return;
+    }
+    match swarm.behaviour_mut().kad.bootstrap() {
+        Ok(id) => agent.bootstrap_query = Some(id),
+        Err(_) => log::info!("routing table is empty, waiting for a peer before bootstrapping"),
+    }
+}
@@ -2,4 +2,5 @@ pub mod agent;
 pub mod behaviour;
 pub mod connection_p2p;
 pub mod event;
+pub mod health;
 pub mod command;
\ No newline at end of file
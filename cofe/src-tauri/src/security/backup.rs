@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    StoredPeer,
+    config::{Config, KdfParams},
+    message::{
+        file::file_store::{read_stored_file, write_stored_file, FileRecord, CHUNK_SIZE},
+        group::group_store::Group,
+    },
+    node_identity::{contacts::Contact, devices::DeviceStore},
+    security::{security::derive_storage_key, session_store::PeerSessions, x3dh::SignedPreKey},
+};
+
+// A backup file is
+//   magic  8 bytes "COFEBAK\0"
+//   u32    header length
+//   header json BackupHeader, readable without the password
+//   nonce  12 bytes
+//   u64    length of the sealed payload
+//   AES-256-GCM of the json BackupPayload, with the header as associated data
+//   then, for every complete file in the payload, one frame per chunk of
+//   [u32 length][12 byte nonce][AES-256-GCM of the chunk], bound to the header,
+//   the file's place in the payload and the chunk number
+// The key comes from the backup password through the KDF in the header, so
+// the header cannot be altered without decryption failing.
+
+const MAGIC: &[u8; 8] = b"COFEBAK\0";
+const BACKUP_VERSION: u16 = 1;
+// The header is read before anything is authenticated, bound what it can ask for
+const MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+// nonce + AES-GCM tag
+const FRAME_OVERHEAD: u64 = 12 + 16;
+
+#[derive(Serialize, Deserialize)]
+struct BackupHeader {
+    version: u16,
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    created_at: i64,
+}
+
+/// Everything needed to bring an install back, chats in plaintext json.
+#[derive(Serialize, Deserialize)]
+pub struct BackupPayload {
+    /// Hex encoded ed25519 secret, as kept in the keyring.
+    pub identity: String,
+    pub peers: HashMap<String, StoredPeer>,
+    pub config: Config,
+    pub groups: Vec<Group>,
+    #[serde(default)]
+    pub devices: DeviceStore,
+    #[serde(default)]
+    pub contacts: Vec<Contact>,
+    pub chats: Vec<BackupChat>,
+    /// Ratchet sessions by peer id, so messages already in flight can be read.
+    pub sessions: HashMap<String, PeerSessions>,
+    pub prekey: Option<SignedPreKey>,
+    /// The file index. Complete files follow the payload as chunk frames,
+    /// unfinished downloads are fetched from their sender again.
+    pub files: Vec<FileRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupChat {
+    pub name: String,
+    pub messages: Vec<serde_json::Value>,
+}
+
+/// The file frames after the payload, read by `restore_files`.
+pub struct BackupFiles {
+    input: BufReader<File>,
+    cipher: Aes256Gcm,
+    header: Vec<u8>,
+}
+
+pub fn write_backup(
+    path: &Path,
+    payload: &BackupPayload,
+    password: &str,
+    kdf: &KdfParams,
+    storage_key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let salt = rand::random::<[u8; 16]>();
+    let header = serde_json::to_vec(&BackupHeader {
+        version: BACKUP_VERSION,
+        kdf: kdf.clone(),
+        salt: salt.to_vec(),
+        created_at: chrono::Utc::now().timestamp_millis(),
+    })?;
+
+    let cipher = Aes256Gcm::new_from_slice(&derive_storage_key(password, &salt, kdf)?)?;
+    let nonce = rand::random::<[u8; 12]>();
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &serde_json::to_vec(payload)?, aad: &header })
+        .map_err(|_| "failed to encrypt backup")?;
+
+    // Never leave a half written backup where the user expects a good one
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&(header.len() as u32).to_be_bytes())?;
+    out.write_all(&header)?;
+    out.write_all(&nonce)?;
+    out.write_all(&(sealed.len() as u64).to_be_bytes())?;
+    out.write_all(&sealed)?;
+
+    for (i, record) in payload.files.iter().enumerate().filter(|(_, r)| r.complete) {
+        let mut chunks = 0u64;
+        read_stored_file(&record.local_id(), storage_key, |chunk| {
+            let nonce = rand::random::<[u8; 12]>();
+            let sealed = cipher
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: chunk, aad: &frame_aad(&header, i, chunks) })
+                .map_err(|_| "failed to encrypt backup")?;
+
+            out.write_all(&((nonce.len() + sealed.len()) as u32).to_be_bytes())?;
+            out.write_all(&nonce)?;
+            out.write_all(&sealed)?;
+            chunks += 1;
+            Ok(())
+        })
+        .map_err(|e| format!("attachment {} cannot be backed up: {e}", record.attachment.name))?;
+
+        if chunks != chunk_count(record) {
+            return Err(format!("attachment {} is incomplete on disk", record.attachment.name).into());
+        }
+    }
+
+    out.flush()?;
+    out.get_ref().sync_all()?;
+    drop(out);
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Reads and verifies a backup, failing on a wrong password or any modification.
+/// The files are restored afterwards through `restore_files`.
+pub fn read_backup(
+    path: &Path,
+    password: &str,
+) -> Result<(BackupPayload, BackupFiles), Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut input = BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic).map_err(|_| "not a backup file")?;
+    if &magic != MAGIC {
+        return Err("not a backup file".into());
+    }
+
+    let len = read_u32(&mut input)? as u64;
+    if len > file_len {
+        return Err("backup is truncated".into());
+    }
+    let mut header_bytes = vec![0u8; len as usize];
+    input.read_exact(&mut header_bytes).map_err(|_| "backup is truncated")?;
+    let mut nonce = [0u8; 12];
+    input.read_exact(&mut nonce).map_err(|_| "backup is truncated")?;
+
+    let header: BackupHeader = serde_json::from_slice(&header_bytes)?;
+    if header.version != BACKUP_VERSION {
+        return Err(format!("unsupported backup version {}", header.version).into());
+    }
+    if header.kdf.memory_kib > MAX_KDF_MEMORY_KIB {
+        return Err("backup asks for too much memory to derive its key".into());
+    }
+
+    let mut len = [0u8; 8];
+    input.read_exact(&mut len).map_err(|_| "backup is truncated")?;
+    let len = u64::from_be_bytes(len);
+    if len > file_len {
+        return Err("backup is truncated".into());
+    }
+    let mut sealed = vec![0u8; len as usize];
+    input.read_exact(&mut sealed).map_err(|_| "backup is truncated")?;
+
+    let cipher = Aes256Gcm::new_from_slice(&derive_storage_key(password, &header.salt, &header.kdf)?)?;
+    let json = cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &sealed, aad: &header_bytes })
+        .map_err(|_| "wrong password or damaged backup")?;
+
+    Ok((serde_json::from_slice(&json)?, BackupFiles { input, cipher, header: header_bytes }))
+}
+
+/// Writes every complete file of `files`, the payload's index, into the file
+/// store under `storage_key`.
+pub fn restore_files(
+    backup: &mut BackupFiles,
+    files: &[FileRecord],
+    storage_key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (i, record) in files.iter().enumerate().filter(|(_, r)| r.complete) {
+        let total = chunk_count(record);
+        let mut chunks = 0u64;
+
+        write_stored_file(&record.local_id(), storage_key, || {
+            if chunks == total {
+                return Ok(None);
+            }
+            let chunk = read_frame(backup, i, chunks)?;
+            chunks += 1;
+            Ok(Some(chunk))
+        })
+        .map_err(|e| format!("attachment {} cannot be restored: {e}", record.attachment.name))?;
+    }
+    Ok(())
+}
+
+fn read_frame(backup: &mut BackupFiles, file: usize, chunk: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let len = read_u32(&mut backup.input)? as u64;
+    if !(FRAME_OVERHEAD..=CHUNK_SIZE + FRAME_OVERHEAD).contains(&len) {
+        return Err("damaged backup".into());
+    }
+
+    let mut frame = vec![0u8; len as usize];
+    backup.input.read_exact(&mut frame).map_err(|_| "backup is truncated")?;
+    let (nonce, sealed) = frame.split_at(12);
+
+    Ok(backup
+        .cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: &frame_aad(&backup.header, file, chunk) })
+        .map_err(|_| "damaged backup")?)
+}
+
+fn frame_aad(header: &[u8], file: usize, chunk: u64) -> Vec<u8> {
+    [header, &(file as u64).to_be_bytes(), &chunk.to_be_bytes()].concat()
+}
+
+fn chunk_count(record: &FileRecord) -> u64 {
+    record.attachment.size.div_ceil(CHUNK_SIZE)
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32, Box<dyn std::error::Error>> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes).map_err(|_| "backup is truncated")?;
+    Ok(u32::from_be_bytes(bytes))
+}
@@ -0,0 +1,91 @@
+use libp2p::{identity, PeerId};
+
+use crate::{message::message::DeviceCertificate, security::e2e::ed25519_public};
+
+const DEVICE_CERT_CONTEXT: &[u8] = b"cofe/device-cert/v1";
+const DEVICE_CONSENT_CONTEXT: &[u8] = b"cofe/device-consent/v1";
+
+/// The device's signature agreeing to join `account`, sent with its link
+/// request. Without it an account could claim any peer as its device.
+pub fn consent_to_link(device: &identity::Keypair, account: PeerId) -> Result<Vec<u8>, String> {
+    device
+        .sign(&consent_bytes(&account, &device.public().to_peer_id()))
+        .map_err(|e| e.to_string())
+}
+
+pub fn verify_consent(account: &PeerId, device: &PeerId, consent: &[u8]) -> Result<(), String> {
+    let public = ed25519_public(device)?;
+    if !public.verify(&consent_bytes(account, device), consent) {
+        return Err("device did not agree to join this account".into());
+    }
+    Ok(())
+}
+
+/// Certifies `device` as part of the account whose primary key is `primary`,
+/// `consent` is the device's from its link request.
+pub fn issue_certificate(primary: &identity::Keypair, device: PeerId, consent: Vec<u8>) -> Result<DeviceCertificate, String> {
+    let mut cert = DeviceCertificate {
+        account: primary.public().to_peer_id(),
+        device,
+        issued_at: chrono::Utc::now().timestamp_millis(),
+        signature: vec![],
+        device_signature: consent,
+    };
+    if cert.account == device {
+        return Err("a device cannot certify itself".into());
+    }
+    verify_consent(&cert.account, &device, &cert.device_signature)?;
+
+    cert.signature = primary
+        .sign(&signing_bytes(&cert))
+        .map_err(|e| e.to_string())?;
+    Ok(cert)
+}
+
+pub fn verify_certificate(cert: &DeviceCertificate) -> Result<(), String> {
+    if cert.account == cert.device {
+        return Err("device certificate names the account itself".into());
+    }
+
+    let public = ed25519_public(&cert.account)?;
+    if !public.verify(&signing_bytes(cert), &cert.signature) {
+        return Err("device certificate signature does not match account".into());
+    }
+    verify_consent(&cert.account, &cert.device, &cert.device_signature)
+}
+
+/// Checks a device list received from `sender` and returns the account it
+/// describes. Every certificate must be valid and for the same account, and
+/// only that account or one of its devices may announce it.
+pub fn verify_device_list(certs: &[DeviceCertificate], sender: &PeerId) -> Result<PeerId, String> {
+    let Some(first) = certs.first() else {
+        return Ok(*sender);
+    };
+
+    for cert in certs {
+        if cert.account != first.account {
+            return Err("device list mixes accounts".into());
+        }
+        verify_certificate(cert)?;
+    }
+
+    if first.account != *sender && !certs.iter().any(|c| c.device == *sender) {
+        return Err(format!("{sender} announced devices of {}, which it is not part of", first.account));
+    }
+    Ok(first.account)
+}
+
+fn signing_bytes(cert: &DeviceCertificate) -> Vec<u8> {
+    let mut bytes = DEVICE_CERT_CONTEXT.to_vec();
+    bytes.extend_from_slice(&cert.account.to_bytes());
+    bytes.extend_from_slice(&cert.device.to_bytes());
+    bytes.extend_from_slice(&cert.issued_at.to_be_bytes());
+    bytes
+}
+
+fn consent_bytes(account: &PeerId, device: &PeerId) -> Vec<u8> {
+    let mut bytes = DEVICE_CONSENT_CONTEXT.to_vec();
+    bytes.extend_from_slice(&account.to_bytes());
+    bytes.extend_from_slice(&device.to_bytes());
+    bytes
+}
@@ -0,0 +1,173 @@
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    message::{file::file_store::Attachment, message::ChatEnvelope},
+    security::{
+        ratchet::{RatchetHeader, RatchetSession},
+        session_store::PeerSessions,
+        x3dh::{x3dh_respond, SignedPreKey},
+    },
+    ChatMessage,
+};
+
+const ENVELOPE_INFO: &[u8] = b"cofe/e2e/v3";
+
+/// Everything in a `ChatMessage` that only the two parties may read.
+#[derive(Serialize, Deserialize)]
+struct ChatBody {
+    content: String,
+    #[serde(default)]
+    attachment: Option<Attachment>,
+}
+
+/// X25519 secret bound to the node identity, using the same
+/// hash-and-clamp step ed25519 applies to its own seed.
+pub fn x25519_secret(identity: &identity::Keypair) -> Result<StaticSecret, String> {
+    let ed = identity
+        .clone()
+        .try_into_ed25519()
+        .map_err(|_| "identity is not an ed25519 key".to_string())?;
+
+    let hash = Sha512::digest(ed.secret().as_ref());
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&hash[..32]);
+
+    Ok(StaticSecret::from(bytes))
+}
+
+/// X25519 public key of a remote peer, recovered from the ed25519 key
+/// embedded in its PeerId.
+pub fn x25519_public(peer: &PeerId) -> Result<PublicKey, String> {
+    let ed = ed25519_public(peer)?;
+
+    let montgomery = CompressedEdwardsY(ed.to_bytes())
+        .decompress()
+        .ok_or("invalid ed25519 point in peer id")?
+        .to_montgomery();
+
+    Ok(PublicKey::from(montgomery.to_bytes()))
+}
+
+pub fn ed25519_public(peer: &PeerId) -> Result<identity::ed25519::PublicKey, String> {
+    let multihash = peer.as_ref();
+    if multihash.code() != 0 {
+        return Err("peer id does not embed its public key".into());
+    }
+
+    identity::PublicKey::try_decode_protobuf(multihash.digest())
+        .map_err(|e| e.to_string())?
+        .try_into_ed25519()
+        .map_err(|_| "peer key is not ed25519".to_string())
+}
+
+/// Encrypts `msg` with the peer's current ratchet session and signs the result.
+pub fn seal_message(identity: &identity::Keypair, session: &mut RatchetSession, msg: &ChatMessage) -> Result<ChatEnvelope, String> {
+    let mut envelope = ChatEnvelope {
+        id: msg.id.clone(),
+        from: msg.from,
+        to: msg.to,
+        timestamp: msg.timestamp,
+        header: RatchetHeader { dh: [0; 32], pn: 0, n: 0 },
+        init: session.init,
+        nonce: [0; 12],
+        ciphertext: vec![],
+        signature: vec![],
+        device: None,
+    };
+
+    let body = serde_json::to_vec(&ChatBody {
+        content: msg.content.clone(),
+        attachment: msg.attachment.clone(),
+    })
+    .map_err(|e| e.to_string())?;
+
+    let (header, nonce, ciphertext) = session.encrypt(&body, &envelope_aad(&envelope))?;
+    envelope.header = header;
+    envelope.nonce = nonce;
+    envelope.ciphertext = ciphertext;
+
+    envelope.signature = identity
+        .sign(&signing_bytes(&envelope))
+        .map_err(|e| e.to_string())?;
+
+    Ok(envelope)
+}
+
+/// Checks that the envelope was signed by the key behind `from` and that
+/// `from` is the peer the connection was authenticated with.
+pub fn verify_envelope(envelope: &ChatEnvelope, remote: &PeerId, local: &PeerId) -> Result<(), String> {
+    if envelope.from != *remote {
+        return Err(format!("sender {} does not match connection peer {remote}", envelope.from));
+    }
+
+    if envelope.to != *local {
+        return Err(format!("message addressed to {}, not to us", envelope.to));
+    }
+
+    let public = ed25519_public(&envelope.from)?;
+    if !public.verify(&signing_bytes(envelope), &envelope.signature) {
+        return Err("invalid message signature".into());
+    }
+
+    Ok(())
+}
+
+/// Decrypts an already verified envelope, answering the sender's handshake
+/// first if it carries one we have not seen yet.
+pub fn open_envelope(
+    identity: &identity::Keypair,
+    sessions: &mut PeerSessions,
+    prekey: Option<&SignedPreKey>,
+    envelope: &ChatEnvelope,
+) -> Result<ChatMessage, String> {
+    let aad = envelope_aad(envelope);
+
+    let body = match envelope.init {
+        Some(init) if !sessions.has_origin(&init.ephemeral) => {
+            let prekey = prekey.ok_or("no prekey to answer the handshake")?;
+            let mut session = x3dh_respond(identity, &envelope.from, prekey, &init)?;
+            let body = session.decrypt(&envelope.header, &envelope.nonce, &envelope.ciphertext, &aad)?;
+            sessions.push(session);
+            body
+        }
+        _ => sessions.decrypt(&envelope.header, &envelope.nonce, &envelope.ciphertext, &aad)?,
+    };
+
+    let body: ChatBody = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+
+    Ok(ChatMessage {
+        id: envelope.id.clone(),
+        from: envelope.from,
+        to: envelope.to,
+        timestamp: envelope.timestamp,
+        content: body.content,
+        attachment: body.attachment,
+    })
+}
+
+fn signing_bytes(envelope: &ChatEnvelope) -> Vec<u8> {
+    let mut bytes = ENVELOPE_INFO.to_vec();
+    bytes.extend_from_slice(&envelope_aad(envelope));
+    bytes.extend_from_slice(&envelope.header.to_bytes());
+    bytes.extend_from_slice(&envelope.nonce);
+    bytes.extend_from_slice(&envelope.ciphertext);
+    bytes
+}
+
+//@ Header fields are not secret but must not be swapped between envelopes
+fn envelope_aad(envelope: &ChatEnvelope) -> Vec<u8> {
+    let mut aad = Vec::new();
+    aad.extend_from_slice(&(envelope.id.len() as u32).to_be_bytes());
+    aad.extend_from_slice(envelope.id.as_bytes());
+    aad.extend_from_slice(&envelope.from.to_bytes());
+    aad.extend_from_slice(&envelope.to.to_bytes());
+    aad.extend_from_slice(&envelope.timestamp.to_be_bytes());
+    if let Some(init) = &envelope.init {
+        aad.extend_from_slice(&init.to_bytes());
+    }
+    aad
+}
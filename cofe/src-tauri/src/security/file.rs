@@ -0,0 +1,27 @@
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+
+use crate::message::file::file_store::Attachment;
+
+/// Encrypts one chunk for the wire, bound to its file and offset so chunks
+/// cannot be swapped around by whoever relays them.
+pub fn seal_chunk(attachment: &Attachment, offset: u64, data: &[u8]) -> Result<([u8; 12], Vec<u8>), String> {
+    let nonce = rand::random::<[u8; 12]>();
+
+    let ciphertext = Aes256Gcm::new_from_slice(&attachment.key)
+        .map_err(|e| e.to_string())?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &chunk_aad(&attachment.file_id, offset) })
+        .map_err(|_| "failed to encrypt file chunk")?;
+
+    Ok((nonce, ciphertext))
+}
+
+pub fn open_chunk(attachment: &Attachment, offset: u64, nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>, String> {
+    Aes256Gcm::new_from_slice(&attachment.key)
+        .map_err(|e| e.to_string())?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad: &chunk_aad(&attachment.file_id, offset) })
+        .map_err(|_| "file chunk failed to decrypt".into())
+}
+
+fn chunk_aad(file_id: &str, offset: u64) -> Vec<u8> {
+    [file_id.as_bytes(), &offset.to_be_bytes()].concat()
+}
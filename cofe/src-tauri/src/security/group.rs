@@ -0,0 +1,105 @@
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use libp2p::{identity, PeerId};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    message::{group::group_store::Group, message::{GroupEnvelope, GroupInvite}},
+    security::e2e::{x25519_public, x25519_secret},
+    GroupMessage,
+};
+
+const INVITE_INFO: &[u8] = b"cofe/group-invite/v1";
+
+/// Wraps the group key for `peer` under the static X25519 secret both of
+/// us derive from our identities.
+pub fn wrap_group_key(identity: &identity::Keypair, peer: &PeerId, group: &Group) -> Result<GroupInvite, String> {
+    let key = invite_key(identity, peer, &group.id)?;
+    let nonce = rand::random::<[u8; 12]>();
+
+    let wrapped_key = Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| e.to_string())?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &group.key, aad: group.id.as_bytes() })
+        .map_err(|_| "failed to wrap group key")?;
+
+    Ok(GroupInvite {
+        group_id: group.id.clone(),
+        name: group.name.clone(),
+        admin: group.admin,
+        members: group.members.clone(),
+        nonce,
+        wrapped_key,
+    })
+}
+
+pub fn unwrap_group_key(identity: &identity::Keypair, inviter: &PeerId, invite: &GroupInvite) -> Result<[u8; 32], String> {
+    let key = invite_key(identity, inviter, &invite.group_id)?;
+
+    let group_key = Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| e.to_string())?
+        .decrypt(Nonce::from_slice(&invite.nonce), Payload { msg: &invite.wrapped_key, aad: invite.group_id.as_bytes() })
+        .map_err(|_| "failed to unwrap group key")?;
+
+    group_key
+        .try_into()
+        .map_err(|_| "group key has the wrong length".into())
+}
+
+pub fn seal_group_message(group: &Group, msg: &GroupMessage) -> Result<GroupEnvelope, String> {
+    let mut envelope = GroupEnvelope {
+        group_id: group.id.clone(),
+        id: msg.id.clone(),
+        from: msg.from,
+        timestamp: msg.timestamp,
+        nonce: rand::random::<[u8; 12]>(),
+        ciphertext: vec![],
+    };
+
+    envelope.ciphertext = Aes256Gcm::new_from_slice(&group.key)
+        .map_err(|e| e.to_string())?
+        .encrypt(
+            Nonce::from_slice(&envelope.nonce),
+            Payload { msg: msg.content.as_bytes(), aad: &group_aad(&envelope) },
+        )
+        .map_err(|_| "failed to encrypt group message")?;
+
+    Ok(envelope)
+}
+
+pub fn open_group_message(group: &Group, envelope: &GroupEnvelope) -> Result<GroupMessage, String> {
+    let content = Aes256Gcm::new_from_slice(&group.key)
+        .map_err(|e| e.to_string())?
+        .decrypt(
+            Nonce::from_slice(&envelope.nonce),
+            Payload { msg: &envelope.ciphertext, aad: &group_aad(envelope) },
+        )
+        .map_err(|_| "failed to decrypt group message")?;
+
+    Ok(GroupMessage {
+        id: envelope.id.clone(),
+        group_id: envelope.group_id.clone(),
+        from: envelope.from,
+        timestamp: envelope.timestamp,
+        content: String::from_utf8(content).map_err(|e| e.to_string())?,
+    })
+}
+
+fn invite_key(identity: &identity::Keypair, peer: &PeerId, group_id: &str) -> Result<[u8; 32], String> {
+    let shared = x25519_secret(identity)?.diffie_hellman(&x25519_public(peer)?);
+
+    let mut hasher = Sha256::new();
+    hasher.update(INVITE_INFO);
+    hasher.update(shared.as_bytes());
+    hasher.update(group_id.as_bytes());
+    Ok(hasher.finalize().into())
+}
+
+fn group_aad(envelope: &GroupEnvelope) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for field in [envelope.group_id.as_bytes(), envelope.id.as_bytes()] {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field);
+    }
+    aad.extend_from_slice(&envelope.from.to_bytes());
+    aad.extend_from_slice(&envelope.timestamp.to_be_bytes());
+    aad
+}
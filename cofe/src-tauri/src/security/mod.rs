@@ -1 +1,11 @@
-pub mod security;
\ No newline at end of file
+pub mod security;
+pub mod e2e;
+pub mod ratchet;
+pub mod x3dh;
+pub mod session_store;
+pub mod group;
+pub mod file;
+pub mod rekey;
+pub mod backup;
+pub mod device;
+pub mod safety;
@@ -0,0 +1,330 @@
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::security::x3dh::X3dhInit;
+
+const ROOT_INFO: &[u8] = b"cofe/ratchet/root";
+const MAX_SKIP: u32 = 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RatchetHeader {
+    pub dh: [u8; 32],
+    pub pn: u32,
+    pub n: u32,
+}
+
+impl RatchetHeader {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.dh.to_vec();
+        bytes.extend_from_slice(&self.pn.to_be_bytes());
+        bytes.extend_from_slice(&self.n.to_be_bytes());
+        bytes
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedKey {
+    dh: [u8; 32],
+    n: u32,
+    key: [u8; 32],
+}
+
+/// Double ratchet state for one session with a peer.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatchetSession {
+    dh_secret: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: Vec<SkippedKey>,
+    /// Handshake we started, attached to outgoing messages until the peer answers.
+    pub init: Option<X3dhInit>,
+    /// Ephemeral key of the handshake that created this session on the responder side.
+    pub origin: Option<[u8; 32]>,
+}
+
+impl RatchetSession {
+    pub fn initiator(shared: [u8; 32], remote_prekey: [u8; 32], init: X3dhInit) -> Self {
+        let dh = StaticSecret::from(rand::random::<[u8; 32]>());
+        let (root_key, send_chain) = kdf_rk(
+            &shared,
+            dh.diffie_hellman(&PublicKey::from(remote_prekey)).as_bytes(),
+        );
+
+        Self {
+            dh_secret: dh.to_bytes(),
+            dh_remote: Some(remote_prekey),
+            root_key,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: vec![],
+            init: Some(init),
+            origin: None,
+        }
+    }
+
+    pub fn responder(shared: [u8; 32], prekey_secret: [u8; 32], origin: [u8; 32]) -> Self {
+        Self {
+            dh_secret: prekey_secret,
+            dh_remote: None,
+            root_key: shared,
+            send_chain: None,
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: vec![],
+            init: None,
+            origin: Some(origin),
+        }
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.send_chain.is_some()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<(RatchetHeader, [u8; 12], Vec<u8>), String> {
+        let chain = self.send_chain.ok_or("session cannot send yet")?;
+        let (next_chain, message_key) = kdf_ck(&chain);
+
+        let header = RatchetHeader {
+            dh: PublicKey::from(&StaticSecret::from(self.dh_secret)).to_bytes(),
+            pn: self.pn,
+            n: self.ns,
+        };
+        let nonce = rand::random::<[u8; 12]>();
+        let ciphertext = aead_encrypt(&message_key, &nonce, plaintext, &[aad, &header.to_bytes()].concat())?;
+
+        self.send_chain = Some(next_chain);
+        self.ns += 1;
+
+        Ok((header, nonce, ciphertext))
+    }
+
+    /// Decrypts a message, only committing the new state if it authenticates.
+    pub fn decrypt(&mut self, header: &RatchetHeader, nonce: &[u8; 12], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        let aad = [aad, &header.to_bytes()].concat();
+        let mut next = self.clone();
+
+        if let Some(pos) = next.skipped.iter().position(|k| k.dh == header.dh && k.n == header.n) {
+            let skipped = next.skipped.remove(pos);
+            let plaintext = aead_decrypt(&skipped.key, nonce, ciphertext, &aad)?;
+            *self = next;
+            return Ok(plaintext);
+        }
+
+        if next.dh_remote != Some(header.dh) {
+            next.skip_until(header.pn)?;
+            next.dh_ratchet(header.dh);
+        }
+        next.skip_until(header.n)?;
+
+        let chain = next.recv_chain.ok_or("no receiving chain")?;
+        let (next_chain, message_key) = kdf_ck(&chain);
+        next.recv_chain = Some(next_chain);
+        next.nr += 1;
+
+        let plaintext = aead_decrypt(&message_key, nonce, ciphertext, &aad)?;
+        // The peer evidently has our handshake, stop attaching it
+        next.init = None;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn skip_until(&mut self, until: u32) -> Result<(), String> {
+        let (Some(mut chain), Some(dh)) = (self.recv_chain, self.dh_remote) else {
+            return Ok(());
+        };
+
+        if until > self.nr + MAX_SKIP {
+            return Err("too many skipped messages".into());
+        }
+
+        while self.nr < until {
+            let (next_chain, key) = kdf_ck(&chain);
+            self.skipped.push(SkippedKey { dh, n: self.nr, key });
+            chain = next_chain;
+            self.nr += 1;
+        }
+        self.recv_chain = Some(chain);
+
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, remote: [u8; 32]) {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dh_remote = Some(remote);
+
+        let remote = PublicKey::from(remote);
+        let (root_key, recv_chain) = kdf_rk(
+            &self.root_key,
+            StaticSecret::from(self.dh_secret).diffie_hellman(&remote).as_bytes(),
+        );
+
+        let dh = StaticSecret::from(rand::random::<[u8; 32]>());
+        let (root_key, send_chain) = kdf_rk(&root_key, dh.diffie_hellman(&remote).as_bytes());
+
+        self.dh_secret = dh.to_bytes();
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = Some(send_chain);
+    }
+}
+
+fn kdf_rk(root_key: &[u8; 32], dh_out: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_out)
+        .expand(ROOT_INFO, &mut okm)
+        .expect("64 bytes is a valid hkdf output length");
+
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+fn kdf_ck(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .expect("hmac accepts any key length");
+        mac.update(&[byte]);
+        mac.finalize().into_bytes().into()
+    };
+
+    (step(0x02), step(0x01))
+}
+
+fn aead_encrypt(key: &[u8; 32], nonce: &[u8; 12], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|e| e.to_string())?
+        .encrypt(Nonce::from_slice(nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| "failed to encrypt message".into())
+}
+
+fn aead_decrypt(key: &[u8; 32], nonce: &[u8; 12], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|e| e.to_string())?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "failed to decrypt message".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AAD: &[u8] = b"alice->bob";
+
+    fn pair() -> (RatchetSession, RatchetSession) {
+        let shared = rand::random::<[u8; 32]>();
+        let prekey = StaticSecret::from(rand::random::<[u8; 32]>());
+        let init = X3dhInit {
+            ephemeral: rand::random(),
+            signed_prekey: PublicKey::from(&prekey).to_bytes(),
+        };
+
+        let alice = RatchetSession::initiator(shared, init.signed_prekey, init);
+        let bob = RatchetSession::responder(shared, prekey.to_bytes(), init.ephemeral);
+        (alice, bob)
+    }
+
+    type Sealed = (RatchetHeader, [u8; 12], Vec<u8>);
+
+    fn open(session: &mut RatchetSession, (header, nonce, ciphertext): &Sealed) -> Result<Vec<u8>, String> {
+        session.decrypt(header, nonce, ciphertext, AAD)
+    }
+
+    #[test]
+    fn messages_in_order_both_ways() {
+        let (mut alice, mut bob) = pair();
+        assert!(!bob.can_send());
+
+        for i in 0..3u8 {
+            let sealed = alice.encrypt(&[i], AAD).unwrap();
+            assert_eq!(open(&mut bob, &sealed).unwrap(), [i]);
+        }
+        assert!(bob.can_send());
+
+        let reply = bob.encrypt(b"reply", AAD).unwrap();
+        assert_eq!(open(&mut alice, &reply).unwrap(), b"reply");
+        assert!(alice.init.is_none());
+
+        let sealed = alice.encrypt(b"again", AAD).unwrap();
+        assert_eq!(open(&mut bob, &sealed).unwrap(), b"again");
+    }
+
+    #[test]
+    fn messages_out_of_order() {
+        let (mut alice, mut bob) = pair();
+        let first: Vec<Sealed> = (0..3u8).map(|i| alice.encrypt(&[i], AAD).unwrap()).collect();
+
+        assert_eq!(open(&mut bob, &first[2]).unwrap(), [2]);
+        let reply = bob.encrypt(b"reply", AAD).unwrap();
+        assert_eq!(open(&mut alice, &reply).unwrap(), b"reply");
+
+        // A new ratchet step on alice's side while bob still holds skipped keys
+        let second = alice.encrypt(b"second", AAD).unwrap();
+        assert_eq!(open(&mut bob, &second).unwrap(), b"second");
+        assert_eq!(open(&mut bob, &first[0]).unwrap(), [0]);
+        assert_eq!(open(&mut bob, &first[1]).unwrap(), [1]);
+    }
+
+    #[test]
+    fn skipping_past_the_limit_is_refused() {
+        let (mut alice, mut bob) = pair();
+        let sealed: Vec<Sealed> = (0..MAX_SKIP + 2).map(|_| alice.encrypt(b"hi", AAD).unwrap()).collect();
+
+        let too_far = &sealed[MAX_SKIP as usize + 1];
+        assert_eq!(open(&mut bob, too_far).unwrap_err(), "too many skipped messages");
+
+        // The refused message left the session as it was
+        assert_eq!(open(&mut bob, &sealed[MAX_SKIP as usize]).unwrap(), b"hi");
+        assert_eq!(open(&mut bob, &sealed[0]).unwrap(), b"hi");
+        assert_eq!(open(&mut bob, too_far).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn replay_is_rejected() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.encrypt(b"first", AAD).unwrap();
+        let second = alice.encrypt(b"second", AAD).unwrap();
+
+        assert_eq!(open(&mut bob, &first).unwrap(), b"first");
+        assert!(open(&mut bob, &first).is_err());
+
+        // Also once its key came from the skipped list
+        let third = alice.encrypt(b"third", AAD).unwrap();
+        assert_eq!(open(&mut bob, &third).unwrap(), b"third");
+        assert_eq!(open(&mut bob, &second).unwrap(), b"second");
+        assert!(open(&mut bob, &second).is_err());
+    }
+
+    #[test]
+    fn tampered_message_does_not_advance() {
+        let (mut alice, mut bob) = pair();
+        let (header, nonce, mut ciphertext) = alice.encrypt(b"hello", AAD).unwrap();
+
+        ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&header, &nonce, &ciphertext, AAD).is_err());
+        assert!(!bob.can_send());
+
+        ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&header, &nonce, &ciphertext, b"other").is_err());
+        assert_eq!(bob.decrypt(&header, &nonce, &ciphertext, AAD).unwrap(), b"hello");
+    }
+}
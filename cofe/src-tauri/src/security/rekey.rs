@@ -0,0 +1,231 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use keyring::Entry;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    APP_DATA_DIR,
+    message::{
+        chat::{chat_store::{chat_dir, chat_names, rekey_chat}, outbox::outbox_dir},
+        file::file_store::rekey_files,
+        search::search_index::search_dir,
+    },
+    security::{
+        security::{decrypt, encode_wrapped_key, encrypt, save_wrapped_key, WrappedKey},
+        session_store::session_dir,
+    },
+};
+
+// Changing the storage key re-encrypts everything into rekey-staging/, then
+// swaps each entry below with its staged copy, moving the original into
+// rekey-backup/. Storing the newly wrapped key in the keyring is the commit
+// point, until then a failure or crash puts the backup back. Quarantined chats in
+// recovery/ keep the key they were written with.
+
+const STAGING_DIR: &str = "rekey-staging";
+const BACKUP_DIR: &str = "rekey-backup";
+const JOURNAL: &str = "rekey.json";
+
+const BLOB_DIRS: [&str; 2] = ["outbox", "sessions"];
+const BLOB_FILES: [&str; 4] = ["groups.enc", "prekey.enc", "devices.enc", "contacts.enc"];
+
+#[derive(Serialize, Deserialize)]
+struct RekeyJournal {
+    /// Entries of the app data dir being swapped.
+    entries: Vec<String>,
+    /// The subset that existed before, and so has a copy in the backup.
+    existing: Vec<String>,
+    /// Keyring value once the change is committed.
+    commit_marker: String,
+}
+
+/// Re-encrypts all local state from `old_key` to `new_key`, which `wrapped`
+/// holds under the user's password.
+pub fn rekey_storage(
+    old_key: &[u8; 32],
+    new_key: &[u8; 32],
+    wrapped: &WrappedKey,
+    entry: &Entry,
+) -> Result<(), String> {
+    let root = app_dir();
+    let staging = root.join(STAGING_DIR);
+    let backup = root.join(BACKUP_DIR);
+
+    remove_path(&staging).map_err(|e| e.to_string())?;
+    let entries = match stage(&root, &staging, old_key, new_key) {
+        Ok(entries) => entries,
+        Err(e) => {
+            remove_path(&staging).ok();
+            return Err(format!("failed to re-encrypt storage: {e}"));
+        }
+    };
+
+    if let Err(e) = swap(&root, &staging, &backup, &entries, &encode_wrapped_key(wrapped)) {
+        rollback(&root, &staging, &backup).ok();
+        remove_path(&staging).ok();
+        return Err(format!("failed to replace storage: {e}"));
+    }
+
+    if let Err(e) = save_wrapped_key(wrapped, entry) {
+        rollback(&root, &staging, &backup).map_err(|e| format!("rollback failed, restore from {BACKUP_DIR}: {e}"))?;
+        return Err(format!("failed to store the new key: {e}"));
+    }
+
+    remove_path(&backup).ok();
+    remove_path(&staging).ok();
+    // Derived from the chats, it rebuilds itself under the new key
+    remove_path(&search_dir()).ok();
+    Ok(())
+}
+
+/// Finishes or undoes a key change that was cut short by a crash.
+pub fn recover_interrupted_rekey(entry: &Entry) {
+    let root = app_dir();
+    let staging = root.join(STAGING_DIR);
+    let backup = root.join(BACKUP_DIR);
+
+    if backup.exists() {
+        let committed = fs::read(backup.join(JOURNAL))
+            .ok()
+            .and_then(|json| serde_json::from_slice::<RekeyJournal>(&json).ok())
+            .zip(entry.get_password().ok())
+            .is_some_and(|(journal, stored)| journal.commit_marker == stored);
+
+        let result = if committed {
+            log::info!("completing interrupted key change");
+            remove_path(&backup).map_err(|e| e.into())
+        } else {
+            log::warn!("rolling back interrupted key change");
+            rollback(&root, &staging, &backup)
+        };
+        if let Err(e) = result {
+            log::error!("failed to recover from interrupted key change: {e}");
+            return;
+        }
+    }
+
+    remove_path(&staging).ok();
+}
+
+/// Writes a re-encrypted copy of everything under `staging`, returning the
+/// entries that were staged.
+fn stage(
+    root: &Path,
+    staging: &Path,
+    old_key: &[u8; 32],
+    new_key: &[u8; 32],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    fs::create_dir_all(staging)?;
+    let mut entries = Vec::new();
+
+    let chats = staging.join("chats");
+    fs::create_dir_all(&chats)?;
+    for name in chat_names(chat_dir()) {
+        rekey_chat(&name, old_key, new_key, chat_dir(), &chats)?;
+    }
+    entries.push("chats".into());
+
+    for (dir, src) in BLOB_DIRS.iter().zip([outbox_dir(), session_dir()]) {
+        let dst = staging.join(dir);
+        fs::create_dir_all(&dst)?;
+        for file in fs::read_dir(src)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|e| e == "enc") {
+                reencrypt_blob(&path, &dst.join(path.file_name().unwrap()), old_key, new_key)?;
+            }
+        }
+        entries.push(dir.to_string());
+    }
+
+    for file in BLOB_FILES {
+        let src = root.join(file);
+        if src.exists() {
+            reencrypt_blob(&src, &staging.join(file), old_key, new_key)?;
+            entries.push(file.into());
+        }
+    }
+
+    rekey_files(old_key, new_key, &staging.join("files"))?;
+    entries.push("files".into());
+
+    Ok(entries)
+}
+
+fn swap(
+    root: &Path,
+    staging: &Path,
+    backup: &Path,
+    entries: &[String],
+    commit_marker: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    remove_path(backup)?;
+    fs::create_dir_all(backup)?;
+
+    let journal = RekeyJournal {
+        entries: entries.to_vec(),
+        existing: entries.iter().filter(|e| root.join(e).exists()).cloned().collect(),
+        commit_marker: commit_marker.to_string(),
+    };
+    fs::write(backup.join(JOURNAL), serde_json::to_vec(&journal)?)?;
+
+    for entry in entries {
+        let current = root.join(entry);
+        if current.exists() {
+            fs::rename(&current, backup.join(entry))?;
+        }
+        fs::rename(staging.join(entry), current)?;
+    }
+    Ok(())
+}
+
+/// Puts every swapped entry back the way it was.
+fn rollback(root: &Path, staging: &Path, backup: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let journal = match fs::read(backup.join(JOURNAL)) {
+        Ok(json) => serde_json::from_slice::<RekeyJournal>(&json)?,
+        // The journal is written before anything moves
+        Err(_) => return Ok(remove_path(backup)?),
+    };
+
+    for entry in &journal.entries {
+        let current = root.join(entry);
+        let saved = backup.join(entry);
+
+        // Still staged means the swap never got to it, or stopped halfway
+        if staging.join(entry).exists() {
+            if saved.exists() {
+                fs::rename(saved, current)?;
+            }
+            continue;
+        }
+
+        remove_path(&current)?;
+        if journal.existing.contains(entry) {
+            fs::rename(saved, current)?;
+        }
+    }
+
+    Ok(remove_path(backup)?)
+}
+
+fn reencrypt_blob(src: &Path, dst: &Path, old_key: &[u8; 32], new_key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+    let plaintext = decrypt(&fs::read(src)?, old_key)?;
+    fs::write(dst, encrypt(&plaintext, new_key))?;
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        Ok(())
+    }
+}
+
+fn app_dir() -> PathBuf {
+    APP_DATA_DIR.get().expect("app dir not initialized").clone()
+}
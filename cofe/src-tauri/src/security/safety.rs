@@ -0,0 +1,77 @@
+use libp2p::PeerId;
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+
+use crate::security::e2e::ed25519_public;
+
+// Safety numbers follow the Signal scheme: each side's keys are hashed many
+// times into a 30 byte fingerprint, shown as 30 digits. Both halves are put
+// in a fixed order, so the two parties see the same 60 digits.
+
+const FINGERPRINT_VERSION: u8 = 1;
+const FINGERPRINT_ITERATIONS: usize = 5200;
+const FINGERPRINT_LEN: usize = 30;
+const QR_PREFIX: &str = "cofe-safety";
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SafetyNumber {
+    /// 60 digits in groups of five, for reading out loud.
+    pub digits: String,
+    /// Same comparison in a form meant to be shown and scanned as a QR code.
+    pub qr: String,
+}
+
+/// Fingerprint of an account: its primary key and the keys of its devices,
+/// so linking a new device changes it too.
+pub fn account_fingerprint(account: &PeerId, devices: &[PeerId]) -> Result<[u8; FINGERPRINT_LEN], String> {
+    let mut keys = vec![ed25519_public(account)?.to_bytes()];
+    let mut device_keys = devices
+        .iter()
+        .filter(|d| *d != account)
+        .map(|d| ed25519_public(d).map(|k| k.to_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    device_keys.sort();
+    keys.extend(device_keys);
+
+    let input: Vec<u8> = keys.concat();
+    let mut hash = Sha512::new()
+        .chain_update([FINGERPRINT_VERSION])
+        .chain_update(&input)
+        .finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(&input).finalize();
+    }
+
+    let mut fingerprint = [0u8; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&hash[..FINGERPRINT_LEN]);
+    Ok(fingerprint)
+}
+
+pub fn safety_number(local: &[u8; FINGERPRINT_LEN], remote: &[u8; FINGERPRINT_LEN]) -> SafetyNumber {
+    let (first, second) = if local <= remote { (local, remote) } else { (remote, local) };
+
+    let digits: Vec<String> = [first, second]
+        .iter()
+        .flat_map(|fp| fp.chunks(5))
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect();
+
+    SafetyNumber {
+        digits: digits.join(" "),
+        qr: format!("{QR_PREFIX}:{FINGERPRINT_VERSION}:{}:{}", hex::encode(first), hex::encode(second)),
+    }
+}
+
+/// Whether what the user typed or scanned matches `expected`, ignoring spacing.
+pub fn matches_safety_number(expected: &SafetyNumber, input: &str) -> bool {
+    let input = input.trim();
+    if input.starts_with(QR_PREFIX) {
+        return input == expected.qr;
+    }
+
+    let digits: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    digits == expected.digits.replace(' ', "")
+}
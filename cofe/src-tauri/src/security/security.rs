@@ -1,10 +1,15 @@
-use std::{fs, path::PathBuf};
+use std::{cell::Cell, fmt, fs, sync::{RwLock, RwLockReadGuard}};
 
 use aes_gcm::aead::Aead;
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
 use keyring::{Entry};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use tauri::Manager;
 
+use crate::config::{KdfAlgorithm, KdfParams};
+
 
 pub fn encrypt(data: &[u8], key: &[u8; 32]) -> Vec<u8> {
     use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
@@ -15,74 +20,225 @@ pub fn encrypt(data: &[u8], key: &[u8; 32]) -> Vec<u8> {
     [nonce.to_vec(), encrypted].concat()
 }
 
-pub fn decrypt(data: &[u8], key: &[u8; 32]) -> Vec<u8> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityError {
+    /// Shorter than a nonce and tag, the file was cut off.
+    Truncated,
+    /// Authentication failed, either tampering or the wrong key.
+    Decrypt,
+    /// The recorded key derivation parameters are out of range.
+    Kdf,
+    /// The key was replaced while it was in use, see `key_in_use`.
+    StaleKey,
+}
+
+impl fmt::Display for SecurityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecurityError::Truncated => write!(f, "ciphertext is truncated"),
+            SecurityError::Decrypt => write!(f, "decryption failed, wrong key or corrupt data"),
+            SecurityError::Kdf => write!(f, "invalid key derivation parameters"),
+            SecurityError::StaleKey => write!(f, "storage key was replaced, nothing was written"),
+        }
+    }
+}
+
+impl std::error::Error for SecurityError {}
+
+/// Id of the storage key writes must use. Held shared by every write under
+/// the storage key and exclusively while the key is replaced, so nothing is
+/// written under a key that was just retired.
+static KEY_GATE: RwLock<Option<[u8; 32]>> = RwLock::new(None);
+
+thread_local! {
+    /// Gate guards this thread holds, a nested write must not queue behind a rekey.
+    static GATE_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Keeps the key passed to `key_in_use` current until dropped.
+pub struct KeyInUse {
+    guard: Option<RwLockReadGuard<'static, Option<[u8; 32]>>>,
+}
+
+impl Drop for KeyInUse {
+    fn drop(&mut self) {
+        if self.guard.take().is_some() {
+            GATE_DEPTH.with(|depth| depth.set(depth.get() - 1));
+        }
+    }
+}
+
+/// Taken before writing anything under `key`, and before any store lock a
+/// rekey also takes. Fails once another key has replaced it.
+pub fn key_in_use(key: &[u8; 32]) -> Result<KeyInUse, SecurityError> {
+    if GATE_DEPTH.with(|depth| depth.get()) > 0 {
+        return Ok(KeyInUse { guard: None });
+    }
+
+    let guard = KEY_GATE.read().unwrap();
+    if *guard != Some(key_id(key)) {
+        return Err(SecurityError::StaleKey);
+    }
+    GATE_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    Ok(KeyInUse { guard: Some(guard) })
+}
+
+/// Makes `key` the one writes must use.
+pub fn set_current_key(key: &[u8; 32]) {
+    *KEY_GATE.write().unwrap() = Some(key_id(key));
+}
+
+/// Stops every write until a key is set again, for when the app locks.
+pub fn clear_current_key() {
+    *KEY_GATE.write().unwrap() = None;
+}
+
+/// Runs `replace` with every write held off, and makes `new_key` current if
+/// it succeeds.
+pub fn replace_current_key<T, E>(new_key: &[u8; 32], replace: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let mut gate = KEY_GATE.write().unwrap();
+    GATE_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let result = replace();
+    GATE_DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+    if result.is_ok() {
+        *gate = Some(key_id(new_key));
+    }
+    result
+}
+
+fn key_id(key: &[u8; 32]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("hmac accepts any key length");
+    mac.update(b"cofe/key-id");
+    mac.finalize().into_bytes().into()
+}
+
+pub fn decrypt(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, SecurityError> {
     use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+    // nonce + AES-GCM tag
+    if data.len() < 12 + 16 {
+        return Err(SecurityError::Truncated);
+    }
+
     let (nonce, ciphertext) = data.split_at(12);
-    let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| SecurityError::Decrypt)?;
 
-    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).unwrap()
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| SecurityError::Decrypt)
 }
 
-pub fn derive_storage_key(password: &str, salt: &[u8]) -> [u8; 32] {
-    let mut output = [0u8; 32];
+pub fn derive_storage_key(password: &str, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], SecurityError> {
+    let algorithm = match params.algorithm {
+        KdfAlgorithm::Argon2id => Algorithm::Argon2id,
+        KdfAlgorithm::Argon2i => Algorithm::Argon2i,
+        KdfAlgorithm::Argon2d => Algorithm::Argon2d,
+    };
+    let argon_params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(32))
+        .map_err(|_| SecurityError::Kdf)?;
 
-    Argon2::default()
+    let mut output = [0u8; 32];
+    Argon2::new(algorithm, Version::V0x13, argon_params)
         .hash_password_into(password.as_bytes(), salt, &mut output)
-        .unwrap();
+        .map_err(|_| SecurityError::Kdf)?;
 
-    output
+    Ok(output)
 }
 
-pub fn save_storage_key(key: &[u8; 32], entry: &Entry) {
-    entry.set_password(&base64::encode(key)).unwrap();
+/// The storage key as kept in the keyring, wrapped under a key derived from
+/// the password. Only this blob and its verifier ever leave memory.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WrappedKey {
+    pub version: u8,
+    /// Version 1 keys predate this field and used the legacy parameters.
+    #[serde(default = "KdfParams::legacy")]
+    pub kdf: KdfParams,
+    pub salt: Vec<u8>,
+    pub wrapped: Vec<u8>,
+    pub verifier: Vec<u8>,
+}
 
-    log::info!("password: {}", entry.get_password().unwrap());
+/// What the keyring holds, older installs stored the raw key itself.
+pub enum StoredKey {
+    Wrapped(WrappedKey),
+    Legacy([u8; 32]),
 }
 
-pub fn load_storage_key(entry: &Entry) -> Option<[u8; 32]> {
-    log::info!("load_storage_key start");
+const WRAPPED_KEY_VERSION: u8 = 2;
+const VERIFIER_CONTEXT: &[u8] = b"cofe/storage-key-verifier";
 
-    let encoded = match entry.get_password() {
-        Ok(v) => {
-            log::info!("password loaded from keyring");
-            v
-        }
-        Err(e) => {
-            log::warn!("failed to load password from keyring: {e}");
-            return None;
-        }
-    };
+pub fn generate_storage_key() -> [u8; 32] {
+    rand::random::<[u8; 32]>()
+}
 
-    let bytes = base64::decode(&encoded).ok()?;
+pub fn wrap_storage_key(storage_key: &[u8; 32], password: &str, kdf: &KdfParams) -> Result<WrappedKey, SecurityError> {
+    let salt = rand::random::<[u8; 16]>();
+    let kek = derive_storage_key(password, &salt, kdf)?;
 
-    if bytes.len() != 32 {
-        log::error!("invalid key length: {}", bytes.len());
-        return None;
+    Ok(WrappedKey {
+        version: WRAPPED_KEY_VERSION,
+        kdf: kdf.clone(),
+        salt: salt.to_vec(),
+        wrapped: encrypt(storage_key, &kek),
+        verifier: key_verifier(storage_key),
+    })
+}
+
+/// Unlocking is unwrapping, a wrong password fails authentication here.
+pub fn unwrap_storage_key(wrapped: &WrappedKey, password: &str) -> Result<[u8; 32], SecurityError> {
+    let kek = derive_storage_key(password, &wrapped.salt, &wrapped.kdf)?;
+    let storage_key: [u8; 32] = decrypt(&wrapped.wrapped, &kek)?
+        .try_into()
+        .map_err(|_| SecurityError::Decrypt)?;
+
+    if key_verifier(&storage_key) != wrapped.verifier {
+        return Err(SecurityError::Decrypt);
     }
+    Ok(storage_key)
+}
 
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&bytes);
-    Some(key)
+fn key_verifier(storage_key: &[u8; 32]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(storage_key)
+        .expect("hmac accepts any key length");
+    mac.update(VERIFIER_CONTEXT);
+    mac.finalize().into_bytes().to_vec()
 }
 
+/// Keyring value for `wrapped`, also what a key change journals as its commit marker.
+pub fn encode_wrapped_key(wrapped: &WrappedKey) -> String {
+    base64::encode(serde_json::to_vec(wrapped).expect("wrapped key serializes"))
+}
 
-pub fn save_salt_to_disk(
-    app: &tauri::AppHandle,
-    salt: &[u8],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut path: PathBuf = app.path()
-        .app_data_dir()
-        .expect("cannot resolve app data dir");
+pub fn save_wrapped_key(wrapped: &WrappedKey, entry: &Entry) -> Result<(), keyring::Error> {
+    entry.set_password(&encode_wrapped_key(wrapped))
+}
 
-    fs::create_dir_all(&path)?;
+pub fn load_stored_key(entry: &Entry) -> Option<StoredKey> {
+    let encoded = match entry.get_password() {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("failed to load storage key from keyring: {e}");
+            return None;
+        }
+    };
 
-    path.push("salt.bin");
+    let bytes = base64::decode(&encoded).ok()?;
 
-    fs::write(path, salt)?;
+    if let Ok(key) = <[u8; 32]>::try_from(bytes.as_slice()) {
+        return Some(StoredKey::Legacy(key));
+    }
 
-    Ok(())
+    match serde_json::from_slice(&bytes) {
+        Ok(wrapped) => Some(StoredKey::Wrapped(wrapped)),
+        Err(e) => {
+            log::error!("unreadable storage key in keyring: {e}");
+            None
+        }
+    }
 }
 
+/// Salt of the legacy raw-key scheme, only read to migrate old installs.
 pub fn load_salt_from_disk(
     app: &tauri::AppHandle,
 ) -> Option<Vec<u8>> {
@@ -91,3 +247,10 @@ pub fn load_salt_from_disk(
 
     fs::read(path).ok()
 }
+
+pub fn remove_salt_from_disk(app: &tauri::AppHandle) {
+    if let Ok(mut path) = app.path().app_data_dir() {
+        path.push("salt.bin");
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    APP_DATA_DIR,
+    security::{
+        ratchet::{RatchetHeader, RatchetSession},
+        security::{decrypt, encrypt, key_in_use},
+        x3dh::SignedPreKey,
+    },
+};
+
+const MAX_SESSIONS_PER_PEER: usize = 2;
+
+/// Serializes read-modify-write cycles on session files, a ratchet that is
+/// advanced twice from the same snapshot can never decrypt again.
+pub static SESSION_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Ratchet sessions with one peer, newest first. The previous session is kept
+/// so messages still in flight when both sides handshake at once can be read.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PeerSessions {
+    sessions: Vec<RatchetSession>,
+}
+
+impl PeerSessions {
+    pub fn current_mut(&mut self) -> Option<&mut RatchetSession> {
+        self.sessions.first_mut().filter(|s| s.can_send())
+    }
+
+    pub fn has_origin(&self, ephemeral: &[u8; 32]) -> bool {
+        self.sessions.iter().any(|s| s.origin.as_ref() == Some(ephemeral))
+    }
+
+    pub fn push(&mut self, session: RatchetSession) {
+        self.sessions.insert(0, session);
+        self.sessions.truncate(MAX_SESSIONS_PER_PEER);
+    }
+
+    pub fn decrypt(&mut self, header: &RatchetHeader, nonce: &[u8; 12], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        let mut found = None;
+        for (i, session) in self.sessions.iter_mut().enumerate() {
+            if let Ok(plaintext) = session.decrypt(header, nonce, ciphertext, aad) {
+                found = Some((i, plaintext));
+                break;
+            }
+        }
+
+        if let Some((i, plaintext)) = found {
+            // Whatever the peer is using now is what we answer with
+            let session = self.sessions.remove(i);
+            self.sessions.insert(0, session);
+            return Ok(plaintext);
+        }
+        Err("no session can decrypt this message".into())
+    }
+}
+
+pub fn load_sessions(
+    peer_id: &str,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<PeerSessions, Box<dyn std::error::Error>> {
+    let mut path = base_dir;
+    path.push(format!("{}.enc", peer_id));
+
+    if !path.exists() {
+        return Ok(PeerSessions::default());
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    Ok(serde_json::from_slice(&decrypted)?)
+}
+
+pub fn save_sessions(
+    peer_id: &str,
+    sessions: &PeerSessions,
+    key: &[u8; 32],
+    base_dir: PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let json = serde_json::to_vec(sessions)?;
+    let encrypted = encrypt(&json, key);
+
+    let mut path = base_dir;
+    path.push(format!("{}.enc", peer_id));
+
+    fs::write(path, encrypted)?;
+    Ok(())
+}
+
+/// Peers we have ratchet sessions with.
+pub fn session_peers(base_dir: PathBuf) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(base_dir) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            name.strip_suffix(".enc").map(|s| s.to_string())
+        })
+        .collect()
+}
+
+pub fn load_prekey(
+    key: &[u8; 32],
+) -> Result<Option<SignedPreKey>, Box<dyn std::error::Error>> {
+    let path = prekey_path();
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let encrypted = fs::read(path)?;
+    let decrypted = decrypt(&encrypted, key)?;
+
+    Ok(Some(serde_json::from_slice(&decrypted)?))
+}
+
+pub fn save_prekey(
+    prekey: &SignedPreKey,
+    key: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _key = key_in_use(key)?;
+    let json = serde_json::to_vec(prekey)?;
+    fs::write(prekey_path(), encrypt(&json, key))?;
+    Ok(())
+}
+
+fn prekey_path() -> PathBuf {
+    let mut path = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    path.push("prekey.enc");
+    path
+}
+
+pub fn session_dir() -> PathBuf {
+    let mut dir = APP_DATA_DIR.get().expect("app dir not initialized").clone();
+    dir.push("sessions");
+    std::fs::create_dir_all(&dir).ok();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity;
+
+    use super::*;
+    use crate::security::{
+        security::set_current_key,
+        x3dh::{generate_prekey, x3dh_initiate, x3dh_respond},
+    };
+
+    #[test]
+    fn sessions_survive_a_save_load_round_trip() {
+        let dir = std::env::temp_dir().join(format!("cofe-sessions-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = rand::random::<[u8; 32]>();
+        set_current_key(&key);
+
+        let alice = identity::Keypair::generate_ed25519();
+        let bob = identity::Keypair::generate_ed25519();
+        let alice_id = alice.public().to_peer_id();
+        let prekey = generate_prekey(&bob).unwrap();
+
+        let mut initiator = x3dh_initiate(&alice, &bob.public().to_peer_id(), &prekey.bundle).unwrap();
+        let init = initiator.init.unwrap();
+        let mut sessions = PeerSessions::default();
+        sessions.push(x3dh_respond(&bob, &alice_id, &prekey, &init).unwrap());
+
+        let (h1, n1, c1) = initiator.encrypt(b"first", b"").unwrap();
+        let (h2, n2, c2) = initiator.encrypt(b"second", b"").unwrap();
+        let (h3, n3, c3) = initiator.encrypt(b"third", b"").unwrap();
+        assert_eq!(sessions.decrypt(&h2, &n2, &c2, b"").unwrap(), b"second");
+        save_sessions(&alice_id.to_string(), &sessions, &key, dir.clone()).unwrap();
+
+        let mut loaded = load_sessions(&alice_id.to_string(), &key, dir.clone()).unwrap();
+        assert!(loaded.has_origin(&init.ephemeral));
+        assert!(loaded.decrypt(&h2, &n2, &c2, b"").is_err());
+        assert_eq!(loaded.decrypt(&h1, &n1, &c1, b"").unwrap(), b"first");
+        assert_eq!(loaded.decrypt(&h3, &n3, &c3, b"").unwrap(), b"third");
+
+        let (header, nonce, ciphertext) = loaded.current_mut().unwrap().encrypt(b"reply", b"").unwrap();
+        assert_eq!(initiator.decrypt(&header, &nonce, &ciphertext, b"").unwrap(), b"reply");
+
+        assert_eq!(session_peers(dir.clone()), [alice_id.to_string()]);
+        assert!(load_sessions(&alice_id.to_string(), &rand::random(), dir.clone()).is_err());
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,172 @@
+use hkdf::Hkdf;
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::security::{
+    e2e::{ed25519_public, x25519_public, x25519_secret},
+    ratchet::RatchetSession,
+};
+
+const PREKEY_CONTEXT: &[u8] = b"cofe/prekey";
+const X3DH_INFO: &[u8] = b"cofe/x3dh/v1";
+
+/// Public half of our signed prekey, handed out on `GreetRequest::PreKeyRequest`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PreKeyBundle {
+    pub signed_prekey: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// Handshake data the initiator attaches to its first messages.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct X3dhInit {
+    pub ephemeral: [u8; 32],
+    pub signed_prekey: [u8; 32],
+}
+
+impl X3dhInit {
+    pub fn to_bytes(self) -> Vec<u8> {
+        [self.ephemeral, self.signed_prekey].concat()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignedPreKey {
+    secret: [u8; 32],
+    pub bundle: PreKeyBundle,
+}
+
+pub fn generate_prekey(identity: &identity::Keypair) -> Result<SignedPreKey, String> {
+    let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+    let signed_prekey = PublicKey::from(&secret).to_bytes();
+    let signature = identity
+        .sign(&[PREKEY_CONTEXT, &signed_prekey].concat())
+        .map_err(|e| e.to_string())?;
+
+    Ok(SignedPreKey {
+        secret: secret.to_bytes(),
+        bundle: PreKeyBundle { signed_prekey, signature },
+    })
+}
+
+pub fn verify_bundle(bundle: &PreKeyBundle, peer: &PeerId) -> Result<(), String> {
+    let public = ed25519_public(peer)?;
+    if !public.verify(&[PREKEY_CONTEXT, &bundle.signed_prekey].concat(), &bundle.signature) {
+        return Err("prekey bundle signature does not match peer".into());
+    }
+    Ok(())
+}
+
+pub fn x3dh_initiate(identity: &identity::Keypair, peer: &PeerId, bundle: &PreKeyBundle) -> Result<RatchetSession, String> {
+    verify_bundle(bundle, peer)?;
+
+    let local = x25519_secret(identity)?;
+    let remote = x25519_public(peer)?;
+    let prekey = PublicKey::from(bundle.signed_prekey);
+    let ephemeral = StaticSecret::from(rand::random::<[u8; 32]>());
+
+    let shared = shared_secret(&[
+        local.diffie_hellman(&prekey).as_bytes(),
+        ephemeral.diffie_hellman(&remote).as_bytes(),
+        ephemeral.diffie_hellman(&prekey).as_bytes(),
+    ]);
+
+    let init = X3dhInit {
+        ephemeral: PublicKey::from(&ephemeral).to_bytes(),
+        signed_prekey: bundle.signed_prekey,
+    };
+
+    Ok(RatchetSession::initiator(shared, bundle.signed_prekey, init))
+}
+
+pub fn x3dh_respond(identity: &identity::Keypair, peer: &PeerId, prekey: &SignedPreKey, init: &X3dhInit) -> Result<RatchetSession, String> {
+    if init.signed_prekey != prekey.bundle.signed_prekey {
+        return Err("handshake uses an unknown prekey".into());
+    }
+
+    let local = x25519_secret(identity)?;
+    let remote = x25519_public(peer)?;
+    let prekey_secret = StaticSecret::from(prekey.secret);
+    let ephemeral = PublicKey::from(init.ephemeral);
+
+    let shared = shared_secret(&[
+        prekey_secret.diffie_hellman(&remote).as_bytes(),
+        local.diffie_hellman(&ephemeral).as_bytes(),
+        prekey_secret.diffie_hellman(&ephemeral).as_bytes(),
+    ]);
+
+    Ok(RatchetSession::responder(shared, prekey.secret, init.ephemeral))
+}
+
+fn shared_secret(parts: &[&[u8; 32]]) -> [u8; 32] {
+    // X3DH prepends 32 0xFF bytes so the input never looks like a bare curve point
+    let mut ikm = vec![0xFF; 32];
+    for part in parts {
+        ikm.extend_from_slice(*part);
+    }
+
+    let mut output = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(X3DH_INFO, &mut output)
+        .expect("32 bytes is a valid hkdf output length");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_between_two_identities() {
+        let alice = identity::Keypair::generate_ed25519();
+        let bob = identity::Keypair::generate_ed25519();
+        let alice_id = alice.public().to_peer_id();
+        let bob_id = bob.public().to_peer_id();
+        let prekey = generate_prekey(&bob).unwrap();
+
+        let mut initiator = x3dh_initiate(&alice, &bob_id, &prekey.bundle).unwrap();
+        let init = initiator.init.unwrap();
+        let mut responder = x3dh_respond(&bob, &alice_id, &prekey, &init).unwrap();
+        assert_eq!(responder.origin, Some(init.ephemeral));
+
+        let (header, nonce, ciphertext) = initiator.encrypt(b"hello bob", b"").unwrap();
+        assert_eq!(responder.decrypt(&header, &nonce, &ciphertext, b"").unwrap(), b"hello bob");
+
+        let (header, nonce, ciphertext) = responder.encrypt(b"hello alice", b"").unwrap();
+        assert_eq!(initiator.decrypt(&header, &nonce, &ciphertext, b"").unwrap(), b"hello alice");
+    }
+
+    #[test]
+    fn handshake_with_the_wrong_identity_fails() {
+        let alice = identity::Keypair::generate_ed25519();
+        let bob = identity::Keypair::generate_ed25519();
+        let mallory = identity::Keypair::generate_ed25519();
+        let prekey = generate_prekey(&bob).unwrap();
+
+        // A bundle that bob did not sign
+        let forged = generate_prekey(&mallory).unwrap();
+        assert!(x3dh_initiate(&alice, &bob.public().to_peer_id(), &forged.bundle).is_err());
+
+        // The responder derives a different secret than an impostor of alice
+        let mut initiator = x3dh_initiate(&mallory, &bob.public().to_peer_id(), &prekey.bundle).unwrap();
+        let init = initiator.init.unwrap();
+        let mut responder = x3dh_respond(&bob, &alice.public().to_peer_id(), &prekey, &init).unwrap();
+
+        let (header, nonce, ciphertext) = initiator.encrypt(b"hello bob", b"").unwrap();
+        assert!(responder.decrypt(&header, &nonce, &ciphertext, b"").is_err());
+    }
+
+    #[test]
+    fn handshake_for_an_unknown_prekey_is_refused() {
+        let alice = identity::Keypair::generate_ed25519();
+        let bob = identity::Keypair::generate_ed25519();
+        let old = generate_prekey(&bob).unwrap();
+        let current = generate_prekey(&bob).unwrap();
+
+        let initiator = x3dh_initiate(&alice, &bob.public().to_peer_id(), &old.bundle).unwrap();
+        let init = initiator.init.unwrap();
+        assert!(x3dh_respond(&bob, &alice.public().to_peer_id(), &current, &init).is_err());
+    }
+}
@@ -1,14 +1,66 @@
-import { useState } from "react";
+import { useEffect, useRef, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 import MainLayout from "./MainLayout";
 import SplashScreen from "./SplashScreen";
+import UnlockScreen from "./UnlockScreen";
+
+// The idle timer only needs to hear about activity now and then
+const ACTIVITY_THROTTLE_MS = 30_000;
 
 function App() {
   const [ready, setReady] = useState(false);
-  return ready ? (
+  const [locked, setLocked] = useState(false);
+  const [lockReason, setLockReason] = useState<string>();
+  const lastActivity = useRef(0);
+
+  useEffect(() => {
+    if (!ready) return;
+    invoke<boolean>("is_locked").then(setLocked);
+  }, [ready]);
+
+  useEffect(() => {
+    const unlisten = listen<string>("app-locked", (event) => {
+      setLockReason(event.payload);
+      setLocked(true);
+    });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  useEffect(() => {
+    if (!ready || locked) return;
+
+    function onActivity() {
+      const now = Date.now();
+      if (now - lastActivity.current < ACTIVITY_THROTTLE_MS) return;
+      lastActivity.current = now;
+      invoke("report_activity").catch(() => {});
+    }
+
+    const events = ["keydown", "mousedown", "mousemove", "wheel", "touchstart"];
+    events.forEach((e) => window.addEventListener(e, onActivity, { passive: true }));
+    return () => {
+      events.forEach((e) => window.removeEventListener(e, onActivity));
+    };
+  }, [ready, locked]);
+
+  if (!ready) {
+    return <SplashScreen onDone={() => setReady(true)}/>;
+  }
+
+  return locked ? (
+    <UnlockScreen
+      reason={lockReason}
+      onUnlocked={() => {
+        setLocked(false);
+        setLockReason(undefined);
+      }}
+    />
+  ) : (
     <MainLayout/>
-  ): (
-    <SplashScreen onDone={() => setReady(true)}/>
   );
 }
 
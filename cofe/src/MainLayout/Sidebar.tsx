@@ -1,4 +1,6 @@
-export default function Sidebar({mockNodes, setChatState, setCurrentPeer}: {mockNodes: [], setChatState: (state: boolean) => void, setCurrentPeer: (peerId: string) => void}) {
+import type { DiscoveredPeer } from "./index";
+
+export default function Sidebar({mockNodes, setChatState, setCurrentPeer}: {mockNodes: DiscoveredPeer[], setChatState: (state: boolean) => void, setCurrentPeer: (peerId: string) => void}) {
   return (
     <div className="w-72 border-r border-neutral-800 p-4">
       <h2 className="text-sm font-semibold text-neutral-400 mb-3">
@@ -10,7 +12,7 @@ export default function Sidebar({mockNodes, setChatState, setCurrentPeer}: {mock
           key={i}
           onClick={() => {
             setChatState(true);
-            setCurrentPeer(n);
+            setCurrentPeer(n.peer_id);
           }}
           className="p-2 rounded-md hover:bg-neutral-800 cursor-pointer mb-1"
         >
@@ -18,7 +20,10 @@ export default function Sidebar({mockNodes, setChatState, setCurrentPeer}: {mock
             {/* Bucket {n.bucket} */}
           </div>
           <div className="text-sm truncate">
-            {n}
+            {n.peer_id}
+          </div>
+          <div className="text-xs text-neutral-500 truncate">
+            {n.addrs[0]}
           </div>
         </div>
       ))}
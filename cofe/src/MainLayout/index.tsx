@@ -6,8 +6,14 @@ import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
 
 
+export type DiscoveredPeer = {
+  peer_id: string;
+  addrs: string[];
+};
+
+
 export default function MainLayout() {
-  const [peers, setPeers] = useState<[]>([]);
+  const [peers, setPeers] = useState<DiscoveredPeer[]>([]);
   const [chatState, setChatState] = useState(false);
   const [peerId, setPeerId] = useState("");
   const [currentPeer, setCurrentPeer] = useState("");
@@ -16,7 +22,12 @@ export default function MainLayout() {
   const currentPeerRef = useRef(currentPeer);
   
   async function find_peer(peerId: string) {
-    setPeers(await invoke("find_peer", { peerId }));
+    if (!peerId) return;
+    try {
+      setPeers(await invoke<DiscoveredPeer[]>("find_peer", { peerId }));
+    } catch (err) {
+      console.warn("Peer lookup failed", err);
+    }
   }
 
   useEffect(() => {
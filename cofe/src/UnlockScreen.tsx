@@ -0,0 +1,66 @@
+import { invoke } from "@tauri-apps/api/core";
+import { useState } from "react";
+
+type Props = {
+  reason?: string;
+  onUnlocked: () => void;
+};
+
+export default function UnlockScreen({ reason, onUnlocked }: Props) {
+  const [password, setPassword] = useState("");
+  const [error, setError] = useState("");
+  const [loading, setLoading] = useState(false);
+
+  async function submit(e: React.FormEvent) {
+    e.preventDefault();
+    if (!password) return;
+    try {
+      setLoading(true);
+      setError("");
+      await invoke("unlock_app", { password });
+      setPassword("");
+      onUnlocked();
+    } catch (err) {
+      setError(String(err));
+    } finally {
+      setLoading(false);
+    }
+  }
+
+  return (
+    <div className="h-screen w-screen flex items-center justify-center bg-neutral-950 text-white">
+      <div className="w-[380px] bg-neutral-900 rounded-xl p-6 space-y-6 shadow-xl">
+        <div className="flex flex-col items-center gap-3">
+          <img src="/logo.png" className="w-14 h-14" />
+          <h2 className="text-lg font-semibold">Locked</h2>
+          <p className="text-sm text-neutral-400 text-center">
+            {reason === "idle"
+              ? "Locked after a period of inactivity, enter your password to continue"
+              : "Enter your password to unlock your local data"}
+          </p>
+        </div>
+
+        <form onSubmit={submit} className="space-y-4">
+          <input
+            type="password"
+            value={password}
+            onChange={(e) => setPassword(e.target.value)}
+            placeholder="Password"
+            autoFocus
+            className="w-full bg-neutral-800 rounded-md px-3 py-2 text-sm outline-none focus:ring-1 focus:ring-red-600"
+          />
+
+          {error && <p className="text-sm text-red-500">{error}</p>}
+
+          <button
+            type="submit"
+            disabled={loading}
+            className="w-full bg-red-600 hover:bg-red-500 py-2 rounded-md disabled:opacity-50"
+          >
+            {loading ? "Unlocking…" : "Unlock"}
+          </button>
+        </form>
+      </div>
+    </div>
+  );
+}
@@ -0,0 +1,119 @@
+use std::{env, path::PathBuf};
+
+use crate::config::{Config, KadMode, TransportKind};
+
+const KEY_PATH_ENV: &str = "VANADINITE_KEY";
+const CONFIG_PATH_ENV: &str = "VANADINITE_CONFIG";
+
+pub const USAGE: &str = "\
+Usage: vanadinitev1 [OPTIONS]
+
+Options:
+  --config <PATH>      JSON config file, also VANADINITE_CONFIG
+  --listen <ADDR>      Multiaddr to listen on, repeatable
+  --transport <KIND>   tcp or ws, repeatable
+  --peer <ADDR>        Multiaddr ending in /p2p/<PeerId> to connect to, repeatable
+  --kad-mode <MODE>    server, client or auto
+  --key <PATH>         Node key file, also VANADINITE_KEY
+  --log-level <FILTER> Tracing filter such as info or debug
+  -h, --help           Print this help
+";
+
+/// Flags given on the command line, each one overrides the config file.
+#[derive(Debug, Default)]
+pub struct Cli {
+    pub config: Option<PathBuf>,
+    pub listen: Vec<String>,
+    pub transports: Vec<TransportKind>,
+    pub peers: Vec<String>,
+    pub kad_mode: Option<KadMode>,
+    pub key: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub help: bool,
+}
+
+impl Cli {
+    /// Accepts both `--flag value` and `--flag=value`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                cli.help = true;
+                continue;
+            }
+
+            let value = match inline.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(format!("{flag} needs a value")),
+            };
+
+            match flag.as_str() {
+                "--config" => cli.config = Some(PathBuf::from(value)),
+                "--listen" => cli.listen.push(value),
+                "--transport" => cli.transports.push(parse_transport(&value)?),
+                "--peer" => cli.peers.push(value),
+                "--kad-mode" => cli.kad_mode = Some(parse_kad_mode(&value)?),
+                "--key" => cli.key = Some(PathBuf::from(value)),
+                "--log-level" => cli.log_level = Some(value),
+                _ => return Err(format!("unknown option {flag}")),
+            }
+        }
+
+        Ok(cli)
+    }
+
+    /// The config file if one was given, or the defaults, with flags and
+    /// environment variables applied on top.
+    pub fn into_config(self) -> Result<Config, String> {
+        let config_path = self.config.or_else(|| env::var_os(CONFIG_PATH_ENV).map(PathBuf::from));
+        let mut cfg = match config_path {
+            Some(path) => Config::load(&path).map_err(|e| format!("cannot read {}: {e}", path.display()))?,
+            None => Config::default(),
+        };
+
+        if !self.listen.is_empty() {
+            cfg.network.listen_addrs = self.listen;
+        }
+        if !self.transports.is_empty() {
+            cfg.network.transports = self.transports;
+        }
+        if !self.peers.is_empty() {
+            cfg.network.peers = self.peers;
+        }
+        if let Some(mode) = self.kad_mode {
+            cfg.network.kad_mode = mode;
+        }
+        if let Some(key) = self.key.or_else(|| env::var_os(KEY_PATH_ENV).map(PathBuf::from)) {
+            cfg.key_path = key;
+        }
+        if let Some(level) = self.log_level {
+            cfg.log_level = level;
+        }
+
+        cfg.validate()?;
+        Ok(cfg)
+    }
+}
+
+fn parse_transport(value: &str) -> Result<TransportKind, String> {
+    match value {
+        "tcp" => Ok(TransportKind::Tcp),
+        "ws" | "websocket" => Ok(TransportKind::Websocket),
+        _ => Err(format!("unknown transport {value}, expected tcp or ws")),
+    }
+}
+
+fn parse_kad_mode(value: &str) -> Result<KadMode, String> {
+    match value {
+        "server" => Ok(KadMode::Server),
+        "client" => Ok(KadMode::Client),
+        "auto" => Ok(KadMode::Auto),
+        _ => Err(format!("unknown Kademlia mode {value}, expected server, client or auto")),
+    }
+}
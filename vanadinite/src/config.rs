@@ -0,0 +1,140 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+use libp2p::{kad, multiaddr::Protocol, Multiaddr};
+use serde::{Deserialize, Serialize};
+
+/// Bootstrap node settings, read from an optional JSON file and overridden
+/// by command line flags. Laid out like the `cofe` client's `Config`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// Where the node key lives, created on first run.
+    #[serde(default = "default_key_path")]
+    pub key_path: PathBuf,
+    /// A `tracing` env filter, such as `info` or `vanadinitev1=debug`.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde(default = "default_listen_addrs")]
+    pub listen_addrs: Vec<String>,
+    #[serde(default = "default_transports")]
+    pub transports: Vec<TransportKind>,
+    /// Full multiaddrs ending in `/p2p/<PeerId>`, dialed at startup.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default)]
+    pub kad_mode: KadMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Tcp,
+    #[serde(alias = "ws")]
+    Websocket,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KadMode {
+    /// Answer DHT queries from everyone, what a bootstrap node is for.
+    #[default]
+    Server,
+    Client,
+    /// Let Kademlia decide from whether we have confirmed external addresses.
+    Auto,
+}
+
+impl KadMode {
+    pub fn as_kad(self) -> Option<kad::Mode> {
+        match self {
+            KadMode::Server => Some(kad::Mode::Server),
+            KadMode::Client => Some(kad::Mode::Client),
+            KadMode::Auto => None,
+        }
+    }
+}
+
+fn default_key_path() -> PathBuf {
+    PathBuf::from("vanadinite.key")
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+fn default_listen_addrs() -> Vec<String> {
+    vec!["/ip4/0.0.0.0/tcp/8000/ws".into()]
+}
+
+fn default_transports() -> Vec<TransportKind> {
+    vec![TransportKind::Websocket]
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen_addrs: default_listen_addrs(),
+            transports: default_transports(),
+            peers: vec![],
+            kad_mode: KadMode::default(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: NetworkConfig::default(),
+            key_path: default_key_path(),
+            log_level: default_log_level(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let network = &self.network;
+        if network.transports.is_empty() {
+            return Err("At least one transport must be enabled".into());
+        }
+        if network.listen_addrs.is_empty() {
+            return Err("At least one listen address is required".into());
+        }
+
+        for addr in &network.listen_addrs {
+            let addr: Multiaddr = addr.parse().map_err(|e| format!("Invalid listen address {addr}: {e}"))?;
+            let kind = transport_of(&addr);
+            if !network.transports.contains(&kind) {
+                return Err(format!("Listen address {addr} needs the {kind:?} transport enabled"));
+            }
+        }
+
+        for peer in &network.peers {
+            let addr: Multiaddr = peer.parse().map_err(|e| format!("Invalid peer address {peer}: {e}"))?;
+            if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(format!("Peer address {peer} must end in /p2p/<PeerId>"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The transport a listen or dial address goes through.
+pub fn transport_of(addr: &Multiaddr) -> TransportKind {
+    if addr.iter().any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_))) {
+        TransportKind::Websocket
+    } else {
+        TransportKind::Tcp
+    }
+}
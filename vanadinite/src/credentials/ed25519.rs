@@ -1,11 +1,40 @@
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
 use libp2p::identity;
+use tracing::info;
+
+/// Loads the node key from `path`, a hex encoded ed25519 secret. On first run
+/// a new key is generated and written there, readable by the owner only.
+pub fn load_or_create_key(path: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    if path.exists() {
+        let encoded = fs::read_to_string(path)?;
+        let bytes = hex::decode(encoded.trim())?;
+        let sk = identity::ed25519::SecretKey::try_from_bytes(bytes)?;
+        let kp = identity::ed25519::Keypair::from(sk);
+        return Ok(identity::Keypair::from(kp));
+    }
 
+    let kp = identity::ed25519::Keypair::generate();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
-pub fn generate_ed25519_key_id() -> (libp2p::identity::Keypair){
-    let bytes = [250, 117, 135, 27, 36, 145, 93, 153, 158, 82, 26, 83, 0, 230, 57, 134, 169, 27, 211, 5, 36, 233, 32, 222, 140, 229, 119, 87, 255, 83, 217, 89];
-    let sk = libp2p::identity::ed25519::SecretKey::try_from_bytes(bytes).expect("invalid key");
-    let kp = identity::ed25519::Keypair::from(sk);
-    let local_key = identity::Keypair::from(kp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(hex::encode(kp.secret().as_ref()).as_bytes())?;
+    file.sync_all()?;
 
-    (local_key)
-}
\ No newline at end of file
+    info!("generated a new node key at {}", path.display());
+    Ok(identity::Keypair::from(kp))
+}
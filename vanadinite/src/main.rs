@@ -1,22 +1,31 @@
 pub mod credentials;
+mod cli;
+mod config;
 mod message;
 mod p2p;
 mod utils;
 
-use std::{error::Error};
-
-use libp2p::kad;
+use std::{env, error::Error};
 
+use crate::cli::{Cli, USAGE};
 use crate::p2p::p2p::P2P;
 
 #[tokio::main]
 // #[warn(unused_imports)]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let _ = tracing_subscriber::fmt().with_env_filter("info").try_init();
+    let cli = Cli::parse(env::args().skip(1))?;
+    if cli.help {
+        print!("{USAGE}");
+        return Ok(());
+    }
+    let cfg = cli.into_config()?;
+
+    let _ = tracing_subscriber::fmt().with_env_filter(cfg.log_level.as_str()).try_init();
 
-    let local_key = credentials::ed25519::generate_ed25519_key_id();
+    let local_key = credentials::ed25519::load_or_create_key(&cfg.key_path)?;
+    println!("PeerId: {}", local_key.public().to_peer_id());
 
-    let mut p2p_connection_event = P2P::new(local_key);
+    let mut p2p_connection_event = P2P::new(local_key, cfg);
     match p2p_connection_event.create_p2p().await {
         Ok(mut swarm) => {
             p2p_connection_event
@@ -45,8 +45,9 @@ impl Behaviour {
         self.rr.send_response(ch, rs)
     }
 
-    pub fn set_server_mode(&mut self) {
-        self.kad.set_mode(Some(kad::Mode::Server));
+    /// `None` lets Kademlia pick the mode from our confirmed external addresses.
+    pub fn set_kad_mode(&mut self, mode: Option<kad::Mode>) {
+        self.kad.set_mode(mode);
     }
 }
 
@@ -1,9 +1,11 @@
+use crate::config::{Config, TransportKind};
 use crate::message::message::{GreetRequest, GreetResponse};
 use crate::p2p::behaviour::{Behaviour as AgentBehaviour, Event as AgentEvent};
 use libp2p::core::transport::upgrade;
 use libp2p::identity::Keypair;
-use libp2p::{identify,  noise, tcp, yamux, PeerId, StreamProtocol, Swarm, SwarmBuilder, swarm::SwarmEvent, core::transport::OrTransport, core::Transport};
-use libp2p::{request_response};
+use libp2p::core::{muxing::StreamMuxerBox, transport::Boxed};
+use libp2p::{identify,  noise, tcp, yamux, PeerId, StreamProtocol, Swarm, SwarmBuilder, swarm::SwarmEvent, core::Transport};
+use libp2p::{multiaddr::Protocol, request_response, Multiaddr};
 use std::{error::Error, time::Duration};
 
 use libp2p::futures::StreamExt;
@@ -27,31 +29,25 @@ pub enum Role {
 
 pub struct P2P {
     local_key: Keypair,
+    config: Config,
 }
 
 impl P2P {
-    pub fn new(local_key: Keypair) -> Self {
+    pub fn new(local_key: Keypair, config: Config) -> Self {
         Self {
-            local_key: local_key
+            local_key,
+            config,
         }
     }
 
 
     pub async fn create_p2p(&mut self) -> Result<Swarm<AgentBehaviour>, Box<dyn Error>> {
         let local_key = self.local_key.clone();
+        let transports = self.config.network.transports.clone();
         
         let mut swarm = SwarmBuilder::with_existing_identity(local_key.clone())
         .with_tokio()
-        .with_other_transport(move |keypair| {
-            let tcp_transport:  libp2p::tcp::Transport<libp2p::tcp::tokio::Tcp> = tcp::Transport::new(tcp::Config::default().nodelay(true));
-            let ws_transport = libp2p::websocket::Config::new(tcp::tokio::Transport::new(tcp::Config::default()));
-
-            OrTransport::new(ws_transport, tcp_transport)
-                .upgrade(upgrade::Version::V1)
-                .authenticate(noise::Config::new(&local_key).unwrap())
-                .multiplex(yamux::Config::default())
-                .boxed()
-        })?
+        .with_other_transport(move |keypair| build_transport(keypair, &transports))?
         .with_behaviour(|keypair| {
             let local_peer_id = PeerId::from(keypair.clone().public());
 
@@ -80,9 +76,28 @@ impl P2P {
         .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(30)))
         .build();
 
-        swarm.behaviour_mut().set_server_mode();
-        swarm.listen_on("/ip4/0.0.0.0/tcp/8000/ws".parse()?)?;
-        
+        let network = &self.config.network;
+        swarm.behaviour_mut().set_kad_mode(network.kad_mode.as_kad());
+        for addr in &network.listen_addrs {
+            swarm.listen_on(addr.parse()?)?;
+        }
+
+        //@ Hubungkan ke peer yang sudah dikenal, validate() memastikan ada /p2p/<PeerId>
+        for addr in &network.peers {
+            let addr: Multiaddr = addr.parse()?;
+            if let Some(Protocol::P2p(peer_id)) = addr.iter().last() {
+                swarm.behaviour_mut().register_addr_kad(&peer_id, addr.clone());
+            }
+            if let Err(e) = swarm.dial(addr.clone()) {
+                warn!("Failed to dial {addr}: {e}");
+            }
+        }
+        if !network.peers.is_empty() {
+            if let Err(e) = swarm.behaviour_mut().kad.bootstrap() {
+                warn!("Kademlia bootstrap failed: {e:?}");
+            }
+        }
+
 
         Ok(swarm)
     }
@@ -93,7 +108,11 @@ impl P2P {
                     SwarmEvent::NewListenAddr {
                         listener_id,
                         address,
-                    } => info!("NewListenAddr: {listener_id:?} | {address:?}"),
+                    } => {
+                        info!("NewListenAddr: {listener_id:?} | {address:?}");
+                        // What clients put in their bootstrap config
+                        println!("Listening on {}", address.with_p2p(*swarm.local_peer_id()).unwrap_or_else(|a| a));
+                    }
                     SwarmEvent::ConnectionEstablished {
                         peer_id,
                         connection_id,
@@ -197,3 +216,38 @@ impl P2P {
     }
 
 }
+
+/// Every enabled transport, upgraded and combined. Websocket is tried first
+/// since a `/tcp/../ws` address would otherwise be taken by plain TCP.
+fn build_transport(
+    keypair: &Keypair,
+    kinds: &[TransportKind],
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let mut combined: Option<Boxed<(PeerId, StreamMuxerBox)>> = None;
+
+    for kind in [TransportKind::Websocket, TransportKind::Tcp] {
+        if !kinds.contains(&kind) {
+            continue;
+        }
+        let noise = noise::Config::new(keypair)?;
+        let transport = match kind {
+            TransportKind::Tcp => tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise)
+                .multiplex(yamux::Config::default())
+                .boxed(),
+            TransportKind::Websocket => libp2p::websocket::Config::new(tcp::tokio::Transport::new(tcp::Config::default()))
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise)
+                .multiplex(yamux::Config::default())
+                .boxed(),
+        };
+
+        combined = Some(match combined {
+            Some(prev) => prev.or_transport(transport).map(|either, _| either.into_inner()).boxed(),
+            None => transport,
+        });
+    }
+
+    combined.ok_or_else(|| "no transport enabled".into())
+}